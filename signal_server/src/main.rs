@@ -1,11 +1,53 @@
 use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::broadcast;
 use tungstenite::protocol::Message;
 
+mod stun;
+mod turn;
+
 type Sender = Arc<broadcast::Sender<String>>;
 
+const DEFAULT_STUN_PORT: u16 = 3478;
+const DEFAULT_TURN_PORT: u16 = 3478;
+const DEFAULT_TURN_CREDENTIALS: &str = "test:test";
+
+/// Our own STUN/TURN servers, announced to the backend when it connects.
+#[derive(Debug, Clone, Default)]
+struct LocalServers {
+    stun_port: Option<u16>,
+    turn: Option<(u16, turn::Credentials)>,
+}
+
+impl LocalServers {
+    // stun_server/turn_server signals, on the address the backend reached us on
+    fn signals(&self, ip: IpAddr) -> Vec<String> {
+        let mut signals = vec![];
+        if let Some(port) = self.stun_port {
+            let address = SocketAddr::new(ip, port);
+            signals.push(json!({
+                "type": "stun_server",
+                "data": { "urls": [format!("stun:{address}")] },
+            }));
+        }
+        if let Some((port, credentials)) = &self.turn {
+            let address = SocketAddr::new(ip, *port);
+            signals.push(json!({
+                "type": "turn_server",
+                "data": {
+                    "urls": [format!("turn:{address}")],
+                    "username": credentials.username,
+                    "credential": credentials.password,
+                },
+            }));
+        }
+        signals.iter().map(Value::to_string).collect()
+    }
+}
+
 #[tokio::main]
 async fn main() {
     println!("Signal Server");
@@ -16,13 +58,70 @@ async fn main() {
     let tx_1 = Arc::new(tx_1);
     let tx_2 = Arc::new(tx_2);
 
+    // --stun [port]: also answer STUN binding requests, for machines with no public STUN
+    // --turn [port] [--turn-credentials user:pass]: also relay, for testing relayed media
+    let args: Vec<String> = std::env::args().collect();
+    let port_arg = |name: &str, default: u16| {
+        args.iter().position(|arg| arg == name).map(|i| {
+            args.get(i + 1)
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(default)
+        })
+    };
+    let mut stun_port = port_arg("--stun", DEFAULT_STUN_PORT);
+    let turn_port = port_arg("--turn", DEFAULT_TURN_PORT);
+    let credentials = args
+        .iter()
+        .position(|arg| arg == "--turn-credentials")
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_TURN_CREDENTIALS);
+    let credentials = turn::Credentials::parse(credentials)
+        .expect("--turn-credentials should be username:password");
+
+    if let Some(port) = turn_port {
+        let credentials = credentials.clone();
+        tokio::spawn(async move {
+            // relayed addresses are on loopback, peers elsewhere couldn't reach them anyway
+            let address = SocketAddr::from(([127, 0, 0, 1], port));
+            if let Err(err) = turn::serve(address, credentials).await {
+                println!("[turn] failed: {err}");
+            }
+        });
+
+        if stun_port == Some(port) {
+            println!("[stun] port {port} shared with TURN, which answers binding requests");
+            stun_port = None;
+        }
+    }
+
+    if let Some(port) = stun_port {
+        tokio::spawn(async move {
+            if let Err(err) = stun::serve(SocketAddr::from(([0, 0, 0, 0], port))).await {
+                println!("[stun] failed: {err}");
+            }
+        });
+    }
+
+    let local_servers = LocalServers {
+        stun_port: stun_port.or(turn_port),
+        turn: turn_port.map(|port| (port, credentials)),
+    };
+
     tokio::select! {
-        _ = listen("backend", "127.0.0.1:10001", tx_1.clone(), tx_2.clone()) => {}
-        _ = listen("frontend", "127.0.0.1:10002", tx_2.clone(), tx_1.clone()) => {}
+        _ = listen("backend", "127.0.0.1:10001", tx_1.clone(), tx_2.clone(), local_servers) => {}
+        _ = listen("frontend", "127.0.0.1:10002", tx_2.clone(), tx_1.clone(), LocalServers::default()) => {}
     }
 }
 
-async fn listen(label: &'static str, address: &str, tx_forward: Sender, tx_send: Sender) {
+// `local_servers`: tell each connection about our STUN/TURN servers
+async fn listen(
+    label: &'static str,
+    address: &str,
+    tx_forward: Sender,
+    tx_send: Sender,
+    local_servers: LocalServers,
+) {
     println!("[{label}] LISTEN {address}");
 
     let server = TcpListener::bind(address).await.unwrap();
@@ -33,6 +132,7 @@ async fn listen(label: &'static str, address: &str, tx_forward: Sender, tx_send:
             socket,
             tx_forward.clone(),
             tx_send.subscribe(),
+            local_servers.clone(),
         ));
     }
 }
@@ -42,25 +142,32 @@ async fn handle_connection(
     socket: TcpStream,
     tx_forward: Sender,
     mut rx_send: broadcast::Receiver<String>,
+    local_servers: LocalServers,
 ) {
     let address = socket.peer_addr().unwrap();
     println!("[{label}] CONNECTION from {address}");
 
+    let local_signals = local_servers.signals(socket.local_addr().unwrap().ip());
+
     // websocket handshake
     let ws_stream = tokio_tungstenite::accept_async(socket).await.unwrap();
 
     let (mut write, mut read) = ws_stream.split();
 
+    for text in local_signals {
+        println!("[{label}] SEND {}", redacted(&text));
+        write.send(Message::Text(text)).await.unwrap();
+    }
+
     loop {
         tokio::select! {
             Some(m) = read.next() => {
                 match m {
-                    Ok(m) => match m {
-                        Message::Text(text) => {
-                            println!("[{label}] FORWARD {text}");
+                    Ok(m) => {
+                        if let Message::Text(text) = m {
+                            println!("[{label}] FORWARD {}", redacted(&text));
                             tx_forward.send(text).unwrap();
                         }
-                        _ => {}
                     }
                     Err(e) => {
                         println!("[{label}] receive error {e:?}")
@@ -68,9 +175,86 @@ async fn handle_connection(
                 }
             }
             Ok(text) = rx_send.recv() => {
-                println!("[{label}] SEND {text}");
+                println!("[{label}] SEND {}", redacted(&text));
                 write.send(Message::Text(text)).await.unwrap();
             }
         }
     }
 }
+
+// a signal for the log, with any TURN username and credential (turn_server,
+// ice_servers) blanked out
+fn redacted(text: &str) -> String {
+    fn redact(value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object.iter_mut() {
+                    if key == "username" || key == "credential" {
+                        *value = Value::from("<redacted>");
+                    } else {
+                        redact(value);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(redact),
+            _ => {}
+        }
+    }
+
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value.to_string()
+        }
+        // not ours to read, and nothing to hide in it
+        Err(_) => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn_servers(username: &str, password: &str) -> LocalServers {
+        LocalServers {
+            stun_port: Some(3478),
+            turn: Some((
+                3478,
+                turn::Credentials {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                },
+            )),
+        }
+    }
+
+    #[test]
+    fn local_server_signals_are_valid_json() {
+        let signals = turn_servers(r#"us"er"#, r"pa\ss").signals(IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(signals.len(), 2);
+
+        let stun: Value = serde_json::from_str(&signals[0]).unwrap();
+        assert_eq!(stun["type"], "stun_server");
+        assert_eq!(stun["data"]["urls"][0], "stun:127.0.0.1:3478");
+
+        let turn: Value = serde_json::from_str(&signals[1]).unwrap();
+        assert_eq!(turn["type"], "turn_server");
+        assert_eq!(turn["data"]["urls"][0], "turn:127.0.0.1:3478");
+        assert_eq!(turn["data"]["username"], r#"us"er"#);
+        assert_eq!(turn["data"]["credential"], r"pa\ss");
+    }
+
+    #[test]
+    fn logged_signals_hide_credentials() {
+        let signals = turn_servers("alice", "s3cret").signals(IpAddr::from([127, 0, 0, 1]));
+        let logged = redacted(&signals[1]);
+        assert!(!logged.contains("alice") && !logged.contains("s3cret"));
+        assert!(logged.contains("turn:127.0.0.1:3478"));
+
+        let ice_servers = r#"{"type":"ice_servers","data":{"session_id":1,"ice_servers":[{"urls":["turn:host"],"username":"alice","credential":"s3cret"}]}}"#;
+        let logged = redacted(ice_servers);
+        assert!(!logged.contains("alice") && !logged.contains("s3cret"));
+
+        assert_eq!(redacted("not json"), "not json");
+    }
+}
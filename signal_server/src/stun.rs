@@ -0,0 +1,479 @@
+// Minimal RFC 5389 STUN: message encoding/decoding shared with the TURN
+// server, and a server that answers Binding requests with the address they
+// came from, enough for ICE to gather server reflexive candidates without a
+// public STUN server.
+
+use hmac::{Hmac, Mac};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+pub const MAGIC_COOKIE: u32 = 0x2112_a442;
+pub const HEADER_LEN: usize = 20;
+
+// methods, the request/indication/response class is added by `Class`
+pub const BINDING: u16 = 0x0001;
+
+// attributes
+pub const MAPPED_ADDRESS: u16 = 0x0001;
+pub const USERNAME: u16 = 0x0006;
+pub const MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ERROR_CODE: u16 = 0x0009;
+pub const REALM: u16 = 0x0014;
+pub const NONCE: u16 = 0x0015;
+pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const SOFTWARE: u16 = 0x8022;
+pub const FINGERPRINT: u16 = 0x8028;
+
+const SOFTWARE_NAME: &str = "minimal-gstreamer-webrtc signal_server";
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Request,
+    Indication,
+    Success,
+    Error,
+}
+
+impl Class {
+    fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0x0000,
+            Class::Indication => 0x0010,
+            Class::Success => 0x0100,
+            Class::Error => 0x0110,
+        }
+    }
+}
+
+/// A STUN message. Only methods below 0x10 (Binding and TURN's) are handled,
+/// so the method and class bits don't need interleaving.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub method: u16,
+    pub class: Class,
+    pub transaction_id: [u8; 12],
+    pub attributes: Vec<(u16, Vec<u8>)>,
+    // where MESSAGE-INTEGRITY starts in the raw message, for checking it
+    integrity_offset: Option<usize>,
+}
+
+impl Message {
+    pub fn new(method: u16, class: Class, transaction_id: [u8; 12]) -> Self {
+        Self {
+            method,
+            class,
+            transaction_id,
+            attributes: vec![],
+            integrity_offset: None,
+        }
+    }
+
+    /// Response to `request` of the same method.
+    pub fn reply(request: &Message, class: Class) -> Self {
+        Self::new(request.method, class, request.transaction_id)
+    }
+
+    pub fn error(request: &Message, code: u16, reason: &str) -> Self {
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+
+        let mut message = Self::reply(request, Class::Error);
+        message.add(ERROR_CODE, value);
+        message
+    }
+
+    /// None if `data` isn't a well formed STUN message.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[0] & 0xc0 != 0 {
+            return None;
+        }
+
+        let message_type = u16::from_be_bytes([data[0], data[1]]);
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let cookie = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        if cookie != MAGIC_COOKIE
+            || !length.is_multiple_of(4)
+            || HEADER_LEN + length != data.len()
+            || message_type & 0x3ee0 != 0
+        {
+            return None;
+        }
+
+        let class = match message_type & 0x0110 {
+            0x0000 => Class::Request,
+            0x0010 => Class::Indication,
+            0x0100 => Class::Success,
+            _ => Class::Error,
+        };
+        let mut message = Self::new(
+            message_type & !0x0110,
+            class,
+            data[8..HEADER_LEN].try_into().ok()?,
+        );
+
+        let mut offset = HEADER_LEN;
+        while offset + 4 <= data.len() {
+            let attribute_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
+            let attribute_length =
+                u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            let value = data.get(offset + 4..offset + 4 + attribute_length)?;
+
+            if attribute_type == MESSAGE_INTEGRITY {
+                message.integrity_offset = Some(offset);
+            }
+            // only FINGERPRINT may follow MESSAGE-INTEGRITY, and it's not covered by it
+            if message.integrity_offset.is_none() || attribute_type == MESSAGE_INTEGRITY {
+                message.attributes.push((attribute_type, value.to_vec()));
+            }
+
+            offset += 4 + attribute_length.next_multiple_of(4);
+        }
+
+        Some(message)
+    }
+
+    pub fn add(&mut self, attribute_type: u16, value: Vec<u8>) {
+        self.attributes.push((attribute_type, value));
+    }
+
+    pub fn add_xor_address(&mut self, attribute_type: u16, address: SocketAddr) {
+        let value = xor_address(address, &self.transaction_id);
+        self.add(attribute_type, value);
+    }
+
+    pub fn get(&self, attribute_type: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(t, _)| *t == attribute_type)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Every address of an attribute that may be repeated.
+    pub fn get_xor_addresses(&self, attribute_type: u16) -> Vec<SocketAddr> {
+        self.attributes
+            .iter()
+            .filter(|(t, _)| *t == attribute_type)
+            .filter_map(|(_, value)| from_xor_address(value, &self.transaction_id))
+            .collect()
+    }
+
+    pub fn get_string(&self, attribute_type: u16) -> Option<String> {
+        self.get(attribute_type)
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+
+    pub fn get_xor_address(&self, attribute_type: u16) -> Option<SocketAddr> {
+        self.get(attribute_type)
+            .and_then(|value| from_xor_address(value, &self.transaction_id))
+    }
+
+    /// Whether `raw`, the message this was parsed from, carries a valid
+    /// MESSAGE-INTEGRITY for `key`.
+    pub fn check_integrity(&self, raw: &[u8], key: &[u8]) -> bool {
+        let (Some(offset), Some(integrity)) = (self.integrity_offset, self.get(MESSAGE_INTEGRITY))
+        else {
+            return false;
+        };
+
+        // the length as it was when MESSAGE-INTEGRITY was the last attribute
+        let mut covered = raw[..offset].to_vec();
+        set_length(&mut covered, 24);
+
+        let mut mac = Hmac::<sha1::Sha1>::new_from_slice(key).expect("HMAC takes any key");
+        mac.update(&covered);
+        mac.verify_slice(integrity).is_ok()
+    }
+
+    /// With MESSAGE-INTEGRITY for `integrity_key` if given, always with
+    /// SOFTWARE and FINGERPRINT.
+    pub fn encode(&self, integrity_key: Option<&[u8]>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN + 128);
+        data.extend_from_slice(&(self.method | self.class.bits()).to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        data.extend_from_slice(&self.transaction_id);
+
+        for (attribute_type, value) in &self.attributes {
+            push_attribute(&mut data, *attribute_type, value);
+        }
+        push_attribute(&mut data, SOFTWARE, SOFTWARE_NAME.as_bytes());
+
+        if let Some(key) = integrity_key {
+            set_length(&mut data, 24);
+            let mut mac = Hmac::<sha1::Sha1>::new_from_slice(key).expect("HMAC takes any key");
+            mac.update(&data);
+            push_attribute(&mut data, MESSAGE_INTEGRITY, &mac.finalize().into_bytes());
+        }
+
+        set_length(&mut data, 8);
+        let fingerprint = crc32fast::hash(&data) ^ FINGERPRINT_XOR;
+        push_attribute(&mut data, FINGERPRINT, &fingerprint.to_be_bytes());
+
+        data
+    }
+}
+
+pub async fn serve(address: SocketAddr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(address).await?;
+    println!("[stun] LISTEN {address}");
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+
+        match binding_response(&buf[..len], from) {
+            Some(response) => {
+                println!("[stun] BINDING {from}");
+                // one unreachable client mustn't stop the server
+                if let Err(err) = socket.send_to(&response, from).await {
+                    println!("[stun] failed to answer {from}: {err}");
+                }
+            }
+            None => println!("[stun] not a binding request, {len} bytes from {from} ignored"),
+        }
+    }
+}
+
+/// Success response to a Binding request from `from`, None for anything else.
+pub fn binding_response(request: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+    let request = Message::parse(request)?;
+    if request.method != BINDING || request.class != Class::Request {
+        return None;
+    }
+
+    let mut response = Message::reply(&request, Class::Success);
+    response.add_xor_address(XOR_MAPPED_ADDRESS, from);
+    // for RFC 3489 clients
+    response.add(MAPPED_ADDRESS, address(from));
+
+    Some(response.encode(None))
+}
+
+// type, length, value padded to 4 bytes
+fn push_attribute(data: &mut Vec<u8>, attribute_type: u16, value: &[u8]) {
+    data.extend_from_slice(&attribute_type.to_be_bytes());
+    data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    data.extend_from_slice(value);
+    data.resize(data.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+// header length field, counting `extra` bytes of attributes still to come
+fn set_length(data: &mut [u8], extra: usize) {
+    let length = data.len() - HEADER_LEN + extra;
+    data[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+}
+
+fn address(address: SocketAddr) -> Vec<u8> {
+    let mut value = vec![0, family(address.ip())];
+    value.extend_from_slice(&address.port().to_be_bytes());
+    value.extend(octets(address.ip()));
+    value
+}
+
+// port XORed with the cookie's top half, address with the cookie (+ transaction id for IPv6)
+fn xor_address(address: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut value = vec![0, family(address.ip())];
+    let port = address.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(
+        octets(address.ip())
+            .iter()
+            .zip(xor_key(transaction_id))
+            .map(|(octet, key)| octet ^ key),
+    );
+    value
+}
+
+fn from_xor_address(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let octets: Vec<u8> = value
+        .get(4..)?
+        .iter()
+        .zip(xor_key(transaction_id))
+        .map(|(octet, key)| octet ^ key)
+        .collect();
+
+    let ip = match (value[1], octets.len()) {
+        (0x01, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(octets).ok()?)),
+        (0x02, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn xor_key(transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut key = MAGIC_COOKIE.to_be_bytes().to_vec();
+    key.extend_from_slice(transaction_id);
+    key
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn family(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 0x01,
+        IpAddr::V6(_) => 0x02,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION_ID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    // RFC 5769 2.1, short-term credentials with password "VOkJxbRl1RmTxUk/WvJxBt"
+    const SAMPLE_REQUEST: [u8; 108] = [
+        0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74,
+        0x65, 0x73, 0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e,
+        0x00, 0x01, 0xff, 0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36,
+        0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20,
+        0x20, 0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e,
+        0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5,
+        0x7a, 0x3b, 0xcf,
+    ];
+
+    fn binding_request() -> Vec<u8> {
+        Message::new(BINDING, Class::Request, TRANSACTION_ID).encode(None)
+    }
+
+    #[test]
+    fn parses_rfc_5769_sample_request() {
+        let message = Message::parse(&SAMPLE_REQUEST).expect("sample request parses");
+
+        assert_eq!(message.method, BINDING);
+        assert_eq!(message.class, Class::Request);
+        assert_eq!(message.transaction_id, TRANSACTION_ID);
+        assert_eq!(message.get_string(USERNAME).as_deref(), Some("evtj:h6vY"));
+        assert!(message.check_integrity(&SAMPLE_REQUEST, b"VOkJxbRl1RmTxUk/WvJxBt"));
+        assert!(!message.check_integrity(&SAMPLE_REQUEST, b"wrong password"));
+    }
+
+    #[test]
+    fn encode_parse_round_trip() {
+        let mut message = Message::new(0x0003, Class::Error, TRANSACTION_ID);
+        message.add(USERNAME, b"user".to_vec());
+        // padded to 4 bytes on the wire
+        message.add(REALM, b"realm".to_vec());
+
+        let data = message.encode(None);
+        assert_eq!(data.len() % 4, 0);
+
+        let parsed = Message::parse(&data).expect("encoded message parses");
+        assert_eq!(parsed.method, 0x0003);
+        assert_eq!(parsed.class, Class::Error);
+        assert_eq!(parsed.transaction_id, TRANSACTION_ID);
+        assert_eq!(parsed.get_string(USERNAME).as_deref(), Some("user"));
+        assert_eq!(parsed.get_string(REALM).as_deref(), Some("realm"));
+        assert_eq!(parsed.get_string(SOFTWARE).as_deref(), Some(SOFTWARE_NAME));
+        assert!(parsed.get(FINGERPRINT).is_some());
+    }
+
+    #[test]
+    fn encode_with_integrity_round_trip() {
+        let mut message = Message::new(BINDING, Class::Success, TRANSACTION_ID);
+        message.add(USERNAME, b"user".to_vec());
+        let data = message.encode(Some(b"key"));
+
+        let parsed = Message::parse(&data).expect("encoded message parses");
+        assert!(parsed.check_integrity(&data, b"key"));
+        assert!(!parsed.check_integrity(&data, b"other key"));
+        // FINGERPRINT follows MESSAGE-INTEGRITY and isn't kept
+        assert!(parsed.get(FINGERPRINT).is_none());
+    }
+
+    #[test]
+    fn xor_mapped_address_ipv4() {
+        // RFC 5769 2.2
+        let address: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let value = xor_address(address, &TRANSACTION_ID);
+        assert_eq!(value, [0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        assert_eq!(from_xor_address(&value, &TRANSACTION_ID), Some(address));
+    }
+
+    #[test]
+    fn xor_mapped_address_ipv6() {
+        // RFC 5769 2.3
+        let address: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+        let value = xor_address(address, &TRANSACTION_ID);
+        assert_eq!(
+            value,
+            [
+                0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25,
+                0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+            ]
+        );
+        assert_eq!(from_xor_address(&value, &TRANSACTION_ID), Some(address));
+    }
+
+    #[test]
+    fn binding_response_maps_the_source_address() {
+        for from in ["203.0.113.7:40000", "[2001:db8::7]:40000"] {
+            let from: SocketAddr = from.parse().unwrap();
+            let response = binding_response(&binding_request(), from).expect("a response");
+
+            let response = Message::parse(&response).expect("response parses");
+            assert_eq!(response.method, BINDING);
+            assert_eq!(response.class, Class::Success);
+            assert_eq!(response.transaction_id, TRANSACTION_ID);
+            assert_eq!(response.get_xor_address(XOR_MAPPED_ADDRESS), Some(from));
+            assert_eq!(response.get(MAPPED_ADDRESS), Some(address(from).as_slice()));
+        }
+    }
+
+    #[test]
+    fn binding_response_ignores_other_messages() {
+        let from: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let allocate = Message::new(0x0003, Class::Request, TRANSACTION_ID).encode(None);
+        let success = Message::new(BINDING, Class::Success, TRANSACTION_ID).encode(None);
+
+        assert!(binding_response(&allocate, from).is_none());
+        assert!(binding_response(&success, from).is_none());
+        assert!(binding_response(b"not stun", from).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let request = binding_request();
+        let with = |change: fn(&mut Vec<u8>)| {
+            let mut data = request.clone();
+            change(&mut data);
+            data
+        };
+
+        let malformed = [
+            // empty and shorter than a header
+            vec![],
+            request[..HEADER_LEN - 1].to_vec(),
+            // ChannelData, not STUN
+            with(|data| data[0] |= 0x40),
+            // bad magic cookie
+            with(|data| data[4] ^= 0xff),
+            // length not a multiple of 4
+            with(|data| data[3] += 1),
+            // length beyond the end
+            with(|data| data[3] += 4),
+            // extra bytes after the length
+            with(|data| data.extend_from_slice(&[0; 4])),
+            // method bits we don't handle
+            with(|data| data[1] |= 0x20),
+            // attribute running past the end
+            with(|data| data[HEADER_LEN + 3] += 0x40),
+        ];
+        for data in malformed {
+            assert!(Message::parse(&data).is_none(), "parsed {data:02x?}");
+        }
+        assert!(Message::parse(&request).is_some());
+    }
+}
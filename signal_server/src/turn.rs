@@ -0,0 +1,825 @@
+// Minimal RFC 5766 TURN over UDP: Allocate, Refresh, CreatePermission,
+// ChannelBind, Send/Data indications and ChannelData, with long-term
+// credentials for a single user. Enough to test relayed media on localhost,
+// not meant to face the internet.
+
+use md5::{Digest, Md5};
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::stun::{self, Class, Message};
+
+// methods
+const ALLOCATE: u16 = 0x0003;
+const REFRESH: u16 = 0x0004;
+const SEND: u16 = 0x0006;
+const DATA: u16 = 0x0007;
+const CREATE_PERMISSION: u16 = 0x0008;
+const CHANNEL_BIND: u16 = 0x0009;
+
+// attributes
+const CHANNEL_NUMBER: u16 = 0x000c;
+const LIFETIME: u16 = 0x000d;
+const XOR_PEER_ADDRESS: u16 = 0x0012;
+const DATA_ATTRIBUTE: u16 = 0x0013;
+const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const REQUESTED_TRANSPORT: u16 = 0x0019;
+
+const REALM: &str = "minimal-gstreamer-webrtc";
+const UDP: u8 = 17;
+const CHANNELS: std::ops::RangeInclusive<u16> = 0x4000..=0x7fff;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+const MAX_LIFETIME: Duration = Duration::from_secs(3600);
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+
+/// The one user allowed to allocate.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// From "username:password".
+    pub fn parse(s: &str) -> Option<Self> {
+        let (username, password) = s.split_once(':')?;
+        Some(Self {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    // long-term credential key, MD5(username:realm:password)
+    fn key(&self) -> Vec<u8> {
+        Md5::digest(format!("{}:{REALM}:{}", self.username, self.password)).to_vec()
+    }
+}
+
+struct Allocation {
+    relay: Arc<UdpSocket>,
+    relayed_address: SocketAddr,
+    expires: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    // channel number -> peer
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+    relay_task: JoinHandle<()>,
+}
+
+impl Allocation {
+    fn permits(&self, peer: SocketAddr) -> bool {
+        self.permissions
+            .get(&peer.ip())
+            .is_some_and(|expires| *expires > Instant::now())
+    }
+
+    fn channel_for(&self, peer: SocketAddr) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (address, expires))| *address == peer && *expires > Instant::now())
+            .map(|(channel, _)| *channel)
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.relay_task.abort();
+    }
+}
+
+// by client address
+type Allocations = Arc<Mutex<HashMap<SocketAddr, Allocation>>>;
+
+struct Server {
+    socket: Arc<UdpSocket>,
+    credentials: Credentials,
+    key: Vec<u8>,
+    nonce: String,
+    allocations: Allocations,
+}
+
+/// Serve TURN on `address`, relaying from sockets on the same IP.
+/// Binding requests are answered too, so it can stand in for the STUN server.
+pub async fn serve(address: SocketAddr, credentials: Credentials) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(address).await?;
+    println!("[turn] LISTEN {address} user {}", credentials.username);
+    serve_socket(socket, credentials).await
+}
+
+async fn serve_socket(socket: UdpSocket, credentials: Credentials) -> std::io::Result<()> {
+    let socket = Arc::new(socket);
+
+    let nonce: String = rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+
+    let server = Server {
+        socket: socket.clone(),
+        key: credentials.key(),
+        credentials,
+        nonce,
+        allocations: Arc::default(),
+    };
+
+    tokio::spawn(expire(server.allocations.clone()));
+
+    let mut buf = [0u8; 65536];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let data = &buf[..len];
+        if len < CHANNEL_DATA_HEADER_LEN {
+            println!("[turn] {len} bytes from {from} ignored, too short");
+            continue;
+        }
+
+        // ChannelData starts with 0b01, STUN with 0b00
+        if data[0] & 0xc0 == 0x40 {
+            server.on_channel_data(data, from).await;
+            continue;
+        }
+
+        // one unreachable client mustn't stop the server
+        let sent = match stun::binding_response(data, from) {
+            Some(response) => {
+                println!("[turn] BINDING {from}");
+                socket.send_to(&response, from).await.map(|_| ())
+            }
+            None => match Message::parse(data) {
+                Some(message) => server.on_message(&message, data, from).await,
+                None => {
+                    println!("[turn] not a STUN message, {len} bytes from {from} ignored");
+                    Ok(())
+                }
+            },
+        };
+        if let Err(err) = sent {
+            println!("[turn] failed to answer {from}: {err}");
+        }
+    }
+}
+
+impl Server {
+    async fn on_message(
+        &self,
+        message: &Message,
+        raw: &[u8],
+        from: SocketAddr,
+    ) -> std::io::Result<()> {
+        if message.class == Class::Indication {
+            if message.method == SEND {
+                self.on_send(message, from).await;
+            }
+            return Ok(());
+        }
+        if message.class != Class::Request {
+            return Ok(());
+        }
+
+        if let Some(error) = self.authenticate(message, raw) {
+            println!(
+                "[turn] {from} unauthenticated request {:#06x}",
+                message.method
+            );
+            let response = error.encode(None);
+            self.socket.send_to(&response, from).await?;
+            return Ok(());
+        }
+
+        let response = match message.method {
+            ALLOCATE => self.on_allocate(message, from).await,
+            REFRESH => self.on_refresh(message, from),
+            CREATE_PERMISSION => self.on_create_permission(message, from),
+            CHANNEL_BIND => self.on_channel_bind(message, from),
+            method => {
+                println!("[turn] {from} unknown method {method:#06x}");
+                Message::error(message, 400, "Bad Request")
+            }
+        };
+
+        self.socket
+            .send_to(&response.encode(Some(&self.key)), from)
+            .await?;
+        Ok(())
+    }
+
+    // None if the request carries valid credentials, else the error to send
+    fn authenticate(&self, message: &Message, raw: &[u8]) -> Option<Message> {
+        let challenge = |code, reason| {
+            let mut error = Message::error(message, code, reason);
+            error.add(stun::REALM, REALM.into());
+            error.add(stun::NONCE, self.nonce.clone().into());
+            Some(error)
+        };
+
+        if message.get(stun::MESSAGE_INTEGRITY).is_none() {
+            return challenge(401, "Unauthorized");
+        }
+        if message.get_string(stun::NONCE).as_deref() != Some(self.nonce.as_str()) {
+            return challenge(438, "Stale Nonce");
+        }
+        if message.get_string(stun::USERNAME).as_deref() != Some(self.credentials.username.as_str())
+            || !message.check_integrity(raw, &self.key)
+        {
+            return challenge(401, "Unauthorized");
+        }
+
+        None
+    }
+
+    async fn on_allocate(&self, message: &Message, from: SocketAddr) -> Message {
+        if self
+            .allocations
+            .lock()
+            .expect("failed to lock allocations")
+            .contains_key(&from)
+        {
+            return Message::error(message, 437, "Allocation Mismatch");
+        }
+
+        match message
+            .get(REQUESTED_TRANSPORT)
+            .and_then(|value| value.first())
+        {
+            Some(&UDP) => {}
+            Some(_) => return Message::error(message, 442, "Unsupported Transport Protocol"),
+            None => return Message::error(message, 400, "Bad Request"),
+        }
+
+        let relay = match UdpSocket::bind(SocketAddr::new(self.relay_ip(), 0)).await {
+            Ok(relay) => Arc::new(relay),
+            Err(err) => {
+                println!("[turn] {from} failed to bind a relay socket: {err}");
+                return Message::error(message, 508, "Insufficient Capacity");
+            }
+        };
+        let relayed_address = match relay.local_addr() {
+            Ok(address) => address,
+            Err(_) => return Message::error(message, 508, "Insufficient Capacity"),
+        };
+
+        let lifetime = requested_lifetime(message);
+        let relay_task = tokio::spawn(relay_to_client(
+            relay.clone(),
+            self.socket.clone(),
+            self.allocations.clone(),
+            from,
+        ));
+
+        self.allocations
+            .lock()
+            .expect("failed to lock allocations")
+            .insert(
+                from,
+                Allocation {
+                    relay,
+                    relayed_address,
+                    expires: Instant::now() + lifetime,
+                    permissions: HashMap::new(),
+                    channels: HashMap::new(),
+                    relay_task,
+                },
+            );
+        println!(
+            "[turn] {from} ALLOCATE {relayed_address} for {}s",
+            lifetime.as_secs()
+        );
+
+        let mut response = Message::reply(message, Class::Success);
+        response.add_xor_address(XOR_RELAYED_ADDRESS, relayed_address);
+        response.add_xor_address(stun::XOR_MAPPED_ADDRESS, from);
+        response.add(LIFETIME, (lifetime.as_secs() as u32).to_be_bytes().to_vec());
+        response
+    }
+
+    fn on_refresh(&self, message: &Message, from: SocketAddr) -> Message {
+        let mut allocations = self.allocations.lock().expect("failed to lock allocations");
+        if !allocations.contains_key(&from) {
+            return Message::error(message, 437, "Allocation Mismatch");
+        }
+
+        let lifetime = requested_lifetime(message);
+        if lifetime.is_zero() {
+            allocations.remove(&from);
+            println!("[turn] {from} DELETE");
+        } else if let Some(allocation) = allocations.get_mut(&from) {
+            allocation.expires = Instant::now() + lifetime;
+            println!("[turn] {from} REFRESH {}s", lifetime.as_secs());
+        }
+
+        let mut response = Message::reply(message, Class::Success);
+        response.add(LIFETIME, (lifetime.as_secs() as u32).to_be_bytes().to_vec());
+        response
+    }
+
+    fn on_create_permission(&self, message: &Message, from: SocketAddr) -> Message {
+        let peers = message.get_xor_addresses(XOR_PEER_ADDRESS);
+        if peers.is_empty() {
+            return Message::error(message, 400, "Bad Request");
+        }
+
+        let mut allocations = self.allocations.lock().expect("failed to lock allocations");
+        let Some(allocation) = allocations.get_mut(&from) else {
+            return Message::error(message, 437, "Allocation Mismatch");
+        };
+
+        for peer in peers {
+            println!("[turn] {from} PERMISSION {}", peer.ip());
+            allocation
+                .permissions
+                .insert(peer.ip(), Instant::now() + PERMISSION_LIFETIME);
+        }
+
+        Message::reply(message, Class::Success)
+    }
+
+    fn on_channel_bind(&self, message: &Message, from: SocketAddr) -> Message {
+        let channel = message
+            .get(CHANNEL_NUMBER)
+            .filter(|value| value.len() >= 2)
+            .map(|value| u16::from_be_bytes([value[0], value[1]]));
+        let peer = message.get_xor_address(XOR_PEER_ADDRESS);
+        let (Some(channel), Some(peer)) = (channel, peer) else {
+            return Message::error(message, 400, "Bad Request");
+        };
+        if !CHANNELS.contains(&channel) {
+            return Message::error(message, 400, "Bad Request");
+        }
+
+        let mut allocations = self.allocations.lock().expect("failed to lock allocations");
+        let Some(allocation) = allocations.get_mut(&from) else {
+            return Message::error(message, 437, "Allocation Mismatch");
+        };
+
+        // a channel is bound to one peer and a peer to one channel
+        let bound_peer = allocation.channels.get(&channel).map(|(peer, _)| *peer);
+        let bound_channel = allocation.channel_for(peer);
+        if bound_peer.is_some_and(|bound| bound != peer)
+            || bound_channel.is_some_and(|bound| bound != channel)
+        {
+            return Message::error(message, 400, "Bad Request");
+        }
+
+        println!("[turn] {from} CHANNEL {channel:#06x} {peer}");
+        let now = Instant::now();
+        allocation
+            .channels
+            .insert(channel, (peer, now + CHANNEL_LIFETIME));
+        allocation
+            .permissions
+            .insert(peer.ip(), now + PERMISSION_LIFETIME);
+
+        Message::reply(message, Class::Success)
+    }
+
+    // client -> peer, as a Send indication
+    async fn on_send(&self, message: &Message, from: SocketAddr) {
+        let (Some(peer), Some(data)) = (
+            message.get_xor_address(XOR_PEER_ADDRESS),
+            message.get(DATA_ATTRIBUTE),
+        ) else {
+            return;
+        };
+
+        if let Some(relay) = self.relay_for(from, peer) {
+            let _ = relay.send_to(data, peer).await;
+        }
+    }
+
+    // client -> peer, as ChannelData
+    async fn on_channel_data(&self, data: &[u8], from: SocketAddr) {
+        if data.len() < CHANNEL_DATA_HEADER_LEN {
+            return;
+        }
+        let channel = u16::from_be_bytes([data[0], data[1]]);
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let Some(payload) = data.get(CHANNEL_DATA_HEADER_LEN..CHANNEL_DATA_HEADER_LEN + length)
+        else {
+            return;
+        };
+
+        let target = {
+            let allocations = self.allocations.lock().expect("failed to lock allocations");
+            allocations.get(&from).and_then(|allocation| {
+                let (peer, expires) = allocation.channels.get(&channel)?;
+                (*expires > Instant::now()).then(|| (allocation.relay.clone(), *peer))
+            })
+        };
+
+        if let Some((relay, peer)) = target {
+            let _ = relay.send_to(payload, peer).await;
+        }
+    }
+
+    fn relay_for(&self, from: SocketAddr, peer: SocketAddr) -> Option<Arc<UdpSocket>> {
+        let allocations = self.allocations.lock().expect("failed to lock allocations");
+        let allocation = allocations.get(&from)?;
+        if !allocation.permits(peer) {
+            println!("[turn] {from} no permission for {peer}, dropped");
+            return None;
+        }
+        Some(allocation.relay.clone())
+    }
+
+    // relay on the address we were asked to listen on, loopback if that's any address
+    fn relay_ip(&self) -> IpAddr {
+        let ip = self
+            .socket
+            .local_addr()
+            .map(|address| address.ip())
+            .unwrap_or(IpAddr::from([127, 0, 0, 1]));
+        if ip.is_unspecified() {
+            IpAddr::from([127, 0, 0, 1])
+        } else {
+            ip
+        }
+    }
+}
+
+// peer -> client, as ChannelData if the peer has a channel, else a Data indication
+async fn relay_to_client(
+    relay: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    allocations: Allocations,
+    client: SocketAddr,
+) {
+    let mut buf = [0u8; 65536];
+    while let Ok((len, peer)) = relay.recv_from(&mut buf).await {
+        let channel = {
+            let allocations = allocations.lock().expect("failed to lock allocations");
+            let Some(allocation) = allocations.get(&client) else {
+                return;
+            };
+            if !allocation.permits(peer) {
+                println!("[turn] {client} no permission for {peer}, dropped");
+                continue;
+            }
+            allocation.channel_for(peer)
+        };
+
+        let data = match channel {
+            Some(channel) => {
+                let mut data = Vec::with_capacity(4 + len);
+                data.extend_from_slice(&channel.to_be_bytes());
+                data.extend_from_slice(&(len as u16).to_be_bytes());
+                data.extend_from_slice(&buf[..len]);
+                data
+            }
+            None => {
+                let mut indication =
+                    Message::new(DATA, Class::Indication, rand::thread_rng().gen());
+                indication.add_xor_address(XOR_PEER_ADDRESS, peer);
+                indication.add(DATA_ATTRIBUTE, buf[..len].to_vec());
+                indication.encode(None)
+            }
+        };
+
+        let _ = socket.send_to(&data, client).await;
+    }
+}
+
+async fn expire(allocations: Allocations) {
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+
+        let now = Instant::now();
+        let mut allocations = allocations.lock().expect("failed to lock allocations");
+        allocations.retain(|client, allocation| {
+            let alive = allocation.expires > now;
+            if !alive {
+                println!("[turn] {client} EXPIRED {}", allocation.relayed_address);
+            }
+            alive
+        });
+        for allocation in allocations.values_mut() {
+            allocation.permissions.retain(|_, expires| *expires > now);
+            allocation.channels.retain(|_, (_, expires)| *expires > now);
+        }
+    }
+}
+
+// LIFETIME if given, capped, or the default
+fn requested_lifetime(message: &Message) -> Duration {
+    message
+        .get(LIFETIME)
+        .filter(|value| value.len() == 4)
+        .map(|value| {
+            Duration::from_secs(u32::from_be_bytes([value[0], value[1], value[2], value[3]]) as u64)
+        })
+        .map(|lifetime| lifetime.min(MAX_LIFETIME))
+        .unwrap_or(DEFAULT_LIFETIME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn credentials() -> Credentials {
+        Credentials::parse("user:pass").unwrap()
+    }
+
+    // a client of a server on an ephemeral loopback port
+    struct Client {
+        socket: UdpSocket,
+        server: SocketAddr,
+        server_task: JoinHandle<std::io::Result<()>>,
+    }
+
+    impl Client {
+        async fn start() -> Self {
+            let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let server = server_socket.local_addr().unwrap();
+            let server_task = tokio::spawn(serve_socket(server_socket, credentials()));
+
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            Self {
+                socket,
+                server,
+                server_task,
+            }
+        }
+
+        async fn send(&self, data: &[u8]) {
+            self.socket.send_to(data, self.server).await.unwrap();
+        }
+
+        async fn receive(&self) -> Vec<u8> {
+            let mut buf = [0u8; 1500];
+            let len = tokio::time::timeout(TIMEOUT, self.socket.recv(&mut buf))
+                .await
+                .expect("no answer from the TURN server")
+                .unwrap();
+            buf[..len].to_vec()
+        }
+
+        async fn request(&self, request: &Message, key: Option<&[u8]>) -> Message {
+            self.send(&request.encode(key)).await;
+            let response = Message::parse(&self.receive().await).expect("not a STUN message");
+            assert_eq!(response.transaction_id, request.transaction_id);
+            response
+        }
+
+        // the server's nonce, from the challenge to an unauthenticated request
+        async fn nonce(&self) -> String {
+            let challenge = self.request(&allocate_request(None), None).await;
+            challenge.get_string(stun::NONCE).unwrap()
+        }
+
+        // an allocation with the right credentials, and the nonce it was made with
+        async fn allocate(&self) -> (Message, String) {
+            let nonce = self.nonce().await;
+            let response = self
+                .request(
+                    &authenticated(allocate_request(None), &nonce),
+                    Some(&credentials().key()),
+                )
+                .await;
+            (response, nonce)
+        }
+    }
+
+    impl Drop for Client {
+        fn drop(&mut self) {
+            self.server_task.abort();
+        }
+    }
+
+    fn request(method: u16) -> Message {
+        Message::new(method, Class::Request, rand::thread_rng().gen())
+    }
+
+    fn allocate_request(lifetime: Option<u32>) -> Message {
+        let mut request = request(ALLOCATE);
+        request.add(REQUESTED_TRANSPORT, vec![UDP, 0, 0, 0]);
+        if let Some(lifetime) = lifetime {
+            request.add(LIFETIME, lifetime.to_be_bytes().to_vec());
+        }
+        request
+    }
+
+    fn authenticated(mut request: Message, nonce: &str) -> Message {
+        request.add(stun::USERNAME, b"user".to_vec());
+        request.add(stun::REALM, REALM.into());
+        request.add(stun::NONCE, nonce.into());
+        request
+    }
+
+    fn error_code(message: &Message) -> Option<u16> {
+        let value = message.get(stun::ERROR_CODE)?;
+        Some(u16::from(value[2]) * 100 + u16::from(value[3]))
+    }
+
+    fn lifetime(message: &Message) -> Option<u32> {
+        message
+            .get(LIFETIME)
+            .map(|value| u32::from_be_bytes(value.try_into().unwrap()))
+    }
+
+    fn channel_data(channel: u16, payload: &[u8], padding: usize) -> Vec<u8> {
+        let mut data = channel.to_be_bytes().to_vec();
+        data.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        data.extend_from_slice(payload);
+        data.resize(data.len() + padding, 0);
+        data
+    }
+
+    #[test]
+    fn parses_credentials() {
+        let credentials = Credentials::parse("user:pa:ss").unwrap();
+        assert_eq!(credentials.username, "user");
+        assert_eq!(credentials.password, "pa:ss");
+        assert!(Credentials::parse("user").is_none());
+
+        let key = Md5::digest(format!("user:{REALM}:pa:ss")).to_vec();
+        assert_eq!(credentials.key(), key);
+    }
+
+    #[test]
+    fn turn_attributes_round_trip() {
+        let peer = SocketAddr::from(([192, 0, 2, 1], 50000));
+        let mut message = authenticated(allocate_request(Some(300)), "nonce");
+        message.add_xor_address(XOR_PEER_ADDRESS, peer);
+        message.add(CHANNEL_NUMBER, vec![0x40, 0x01, 0, 0]);
+
+        let key = credentials().key();
+        let raw = message.encode(Some(&key));
+        let parsed = Message::parse(&raw).unwrap();
+
+        assert_eq!(parsed.method, ALLOCATE);
+        assert_eq!(parsed.class, Class::Request);
+        assert_eq!(parsed.get(REQUESTED_TRANSPORT), Some(&[UDP, 0, 0, 0][..]));
+        assert_eq!(lifetime(&parsed), Some(300));
+        assert_eq!(parsed.get_xor_address(XOR_PEER_ADDRESS), Some(peer));
+        assert_eq!(parsed.get_string(stun::NONCE).as_deref(), Some("nonce"));
+        assert!(parsed.check_integrity(&raw, &key));
+        assert!(!parsed.check_integrity(&raw, &Credentials::parse("user:nope").unwrap().key()));
+    }
+
+    #[test]
+    fn caps_requested_lifetime() {
+        let lifetime_of = |lifetime: Option<&[u8]>| {
+            let mut message = request(REFRESH);
+            if let Some(lifetime) = lifetime {
+                message.add(LIFETIME, lifetime.to_vec());
+            }
+            requested_lifetime(&message)
+        };
+
+        assert_eq!(lifetime_of(None), DEFAULT_LIFETIME);
+        assert_eq!(
+            lifetime_of(Some(&300u32.to_be_bytes())),
+            Duration::from_secs(300)
+        );
+        assert_eq!(lifetime_of(Some(&86400u32.to_be_bytes())), MAX_LIFETIME);
+        assert_eq!(lifetime_of(Some(&0u32.to_be_bytes())), Duration::ZERO);
+        assert_eq!(lifetime_of(Some(&[0, 1])), DEFAULT_LIFETIME);
+    }
+
+    #[tokio::test]
+    async fn challenges_unauthenticated_requests() {
+        let client = Client::start().await;
+
+        let challenge = client.request(&allocate_request(None), None).await;
+        assert_eq!(challenge.class, Class::Error);
+        assert_eq!(error_code(&challenge), Some(401));
+        assert_eq!(challenge.get_string(stun::REALM).as_deref(), Some(REALM));
+        let nonce = challenge.get_string(stun::NONCE).unwrap();
+
+        let stale = client
+            .request(
+                &authenticated(allocate_request(None), "stale"),
+                Some(&credentials().key()),
+            )
+            .await;
+        assert_eq!(error_code(&stale), Some(438));
+        assert_eq!(stale.get_string(stun::NONCE), Some(nonce.clone()));
+
+        let wrong_key = Credentials::parse("user:wrong").unwrap().key();
+        let wrong = client
+            .request(
+                &authenticated(allocate_request(None), &nonce),
+                Some(&wrong_key),
+            )
+            .await;
+        assert_eq!(error_code(&wrong), Some(401));
+
+        let mut stranger = allocate_request(None);
+        stranger.add(stun::USERNAME, b"stranger".to_vec());
+        stranger.add(stun::REALM, REALM.into());
+        stranger.add(stun::NONCE, nonce.into());
+        let stranger = client.request(&stranger, Some(&credentials().key())).await;
+        assert_eq!(error_code(&stranger), Some(401));
+    }
+
+    #[tokio::test]
+    async fn allocates_with_capped_lifetime() {
+        let client = Client::start().await;
+        let key = credentials().key();
+
+        let nonce = client.nonce().await;
+        let raw_response = {
+            let request = authenticated(allocate_request(Some(86400)), &nonce);
+            client.send(&request.encode(Some(&key))).await;
+            client.receive().await
+        };
+        let response = Message::parse(&raw_response).unwrap();
+
+        assert_eq!(response.class, Class::Success, "{response:?}");
+        assert!(response.check_integrity(&raw_response, &key));
+        assert_eq!(lifetime(&response), Some(MAX_LIFETIME.as_secs() as u32));
+        let relayed = response.get_xor_address(XOR_RELAYED_ADDRESS).unwrap();
+        assert_eq!(relayed.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(
+            response.get_xor_address(stun::XOR_MAPPED_ADDRESS),
+            Some(client.socket.local_addr().unwrap())
+        );
+
+        // one allocation per client
+        let again = client
+            .request(&authenticated(allocate_request(None), &nonce), Some(&key))
+            .await;
+        assert_eq!(error_code(&again), Some(437));
+    }
+
+    #[tokio::test]
+    async fn refresh_with_zero_lifetime_deletes_allocation() {
+        let client = Client::start().await;
+        let key = credentials().key();
+        let (allocated, nonce) = client.allocate().await;
+        assert_eq!(allocated.class, Class::Success);
+
+        let mut refresh = authenticated(request(REFRESH), &nonce);
+        refresh.add(LIFETIME, 0u32.to_be_bytes().to_vec());
+        let response = client.request(&refresh, Some(&key)).await;
+        assert_eq!(response.class, Class::Success);
+        assert_eq!(lifetime(&response), Some(0));
+
+        // gone, so there's nothing left to refresh
+        let response = client
+            .request(&authenticated(request(REFRESH), &nonce), Some(&key))
+            .await;
+        assert_eq!(error_code(&response), Some(437));
+    }
+
+    #[tokio::test]
+    async fn relays_channel_data_both_ways() {
+        let client = Client::start().await;
+        let key = credentials().key();
+        let (allocated, nonce) = client.allocate().await;
+        let relayed = allocated.get_xor_address(XOR_RELAYED_ADDRESS).unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_address = peer.local_addr().unwrap();
+
+        let mut bind = authenticated(request(CHANNEL_BIND), &nonce);
+        bind.add(CHANNEL_NUMBER, vec![0x40, 0x00, 0, 0]);
+        bind.add_xor_address(XOR_PEER_ADDRESS, peer_address);
+        let response = client.request(&bind, Some(&key)).await;
+        assert_eq!(response.class, Class::Success, "{response:?}");
+
+        // padded to 4 bytes, the padding isn't relayed
+        client.send(&channel_data(0x4000, b"abc", 1)).await;
+        let mut buf = [0u8; 64];
+        let (len, from) = tokio::time::timeout(TIMEOUT, peer.recv_from(&mut buf))
+            .await
+            .expect("nothing relayed to the peer")
+            .unwrap();
+        assert_eq!(&buf[..len], b"abc");
+        assert_eq!(from, relayed);
+
+        // cut short, dropped
+        client.send(&channel_data(0x4000, b"abcdef", 0)[..7]).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), peer.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+
+        peer.send_to(b"hello", relayed).await.unwrap();
+        assert_eq!(client.receive().await, channel_data(0x4000, b"hello", 0));
+    }
+
+    #[tokio::test]
+    async fn rejects_channels_out_of_range() {
+        let client = Client::start().await;
+        let key = credentials().key();
+        let (_, nonce) = client.allocate().await;
+
+        let mut bind = authenticated(request(CHANNEL_BIND), &nonce);
+        bind.add(CHANNEL_NUMBER, vec![0x3f, 0xff, 0, 0]);
+        bind.add_xor_address(XOR_PEER_ADDRESS, SocketAddr::from(([127, 0, 0, 1], 9)));
+        let response = client.request(&bind, Some(&key)).await;
+        assert_eq!(error_code(&response), Some(400));
+    }
+}
@@ -1,6 +1,20 @@
 #!/bin/bash
 
-export LD_LIBRARY_PATH=/opt/tritium/gstreamer/lib/x86_64-linux-gnu/ 
-export PKG_CONFIG_PATH=/opt/tritium/gstreamer/lib/x86_64-linux-gnu/pkgconfig/
+# GStreamer built from source, keep in step with gstreamer.prefix in config.toml
+GSTREAMER_PREFIX=${GSTREAMER_PREFIX:-/opt/tritium/gstreamer}
 
-cargo run 
\ No newline at end of file
+# same search as gstreamer.libdir when it's unset
+if [ -z "$GSTREAMER_LIBDIR" ]; then
+    for libdir in "lib/$(uname -m)-linux-gnu" lib64 lib; do
+        if [ -d "$GSTREAMER_PREFIX/$libdir/gstreamer-1.0" ]; then
+            GSTREAMER_LIBDIR=$libdir
+            break
+        fi
+    done
+fi
+GSTREAMER_LIBDIR=${GSTREAMER_LIBDIR:-lib/$(uname -m)-linux-gnu}
+
+export LD_LIBRARY_PATH=$GSTREAMER_PREFIX/$GSTREAMER_LIBDIR/
+export PKG_CONFIG_PATH=$GSTREAMER_PREFIX/$GSTREAMER_LIBDIR/pkgconfig/
+
+cargo run -- --gstreamer-prefix "$GSTREAMER_PREFIX" --gstreamer-libdir "$GSTREAMER_LIBDIR" "$@"
@@ -0,0 +1,415 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Drops and rewrites ICE candidates, e.g. docker bridge addresses or a host
+/// address behind 1:1 NAT. Empty lets every candidate through untouched.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CandidateFilter {
+    /// Drop candidates matching any of these: an address or CIDR
+    /// ("172.17.0.0/16"), "mdns", "link-local", "loopback", "ipv4", "ipv6",
+    /// a transport ("udp", "tcp") or a candidate type ("host", "srflx", "prflx", "relay").
+    pub drop: Vec<DropPattern>,
+
+    /// Host candidate addresses to replace, for those that weren't dropped.
+    pub rewrite: Vec<AddressRewrite>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddressRewrite {
+    pub from: IpAddr,
+    pub to: IpAddr,
+}
+
+/// What a filter did with a candidate, for logging.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Keep,
+    Drop {
+        pattern: String,
+    },
+    Rewrite {
+        candidate: String,
+        from: IpAddr,
+        to: IpAddr,
+    },
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::Keep => write!(f, "kept"),
+            Decision::Drop { pattern } => write!(f, "dropped ({pattern})"),
+            Decision::Rewrite { from, to, .. } => write!(f, "rewritten {from} → {to}"),
+        }
+    }
+}
+
+/// One of `CandidateFilter::drop`, parsed as the config is read so bad ones
+/// are reported up front and candidates are matched without parsing again.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DropPattern {
+    text: String,
+    pattern: Pattern,
+}
+
+impl FromStr for DropPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Ok(Self {
+            text: text.to_string(),
+            pattern: Pattern::parse(text)?,
+        })
+    }
+}
+
+impl TryFrom<String> for DropPattern {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for DropPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Pattern {
+    Network(IpAddr, u8),
+    Mdns,
+    LinkLocal,
+    Loopback,
+    Ipv4,
+    Ipv6,
+    Transport(String),
+    Type(String),
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        let pattern = pattern.to_lowercase();
+        Ok(match pattern.as_str() {
+            "mdns" => Pattern::Mdns,
+            "link-local" => Pattern::LinkLocal,
+            "loopback" => Pattern::Loopback,
+            "ipv4" => Pattern::Ipv4,
+            "ipv6" => Pattern::Ipv6,
+            "udp" | "tcp" => Pattern::Transport(pattern),
+            "host" | "srflx" | "prflx" | "relay" => Pattern::Type(pattern),
+            _ => {
+                let (address, prefix) = match pattern.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (pattern.as_str(), None),
+                };
+                let address: IpAddr = address
+                    .parse()
+                    .map_err(|_| anyhow!("unknown candidate pattern {pattern:?}"))?;
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix
+                        .parse()
+                        .ok()
+                        .filter(|prefix| *prefix <= max_prefix)
+                        .ok_or_else(|| anyhow!("bad prefix length in {pattern:?}"))?,
+                    None => max_prefix,
+                };
+                Pattern::Network(address, prefix)
+            }
+        })
+    }
+
+    fn matches(&self, candidate: &Candidate) -> bool {
+        match self {
+            Pattern::Mdns => candidate.address.ends_with(".local"),
+            Pattern::Transport(transport) => candidate.transport == *transport,
+            Pattern::Type(candidate_type) => candidate.candidate_type == *candidate_type,
+            _ => {
+                let Ok(address) = candidate.address.parse::<IpAddr>() else {
+                    return false;
+                };
+                match self {
+                    Pattern::Network(network, prefix) => in_network(address, *network, *prefix),
+                    Pattern::LinkLocal => is_link_local(address),
+                    Pattern::Loopback => address.is_loopback(),
+                    Pattern::Ipv4 => address.is_ipv4(),
+                    Pattern::Ipv6 => address.is_ipv6(),
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+// the fields of "candidate:foundation component transport priority address port typ type ..."
+// (RFC 8839) we filter on
+struct Candidate {
+    transport: String,
+    address: String,
+    candidate_type: String,
+}
+
+impl Candidate {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if !fields.first()?.contains("candidate:") || fields.get(6) != Some(&"typ") {
+            return None;
+        }
+        Some(Self {
+            transport: fields[2].to_lowercase(),
+            address: fields[4].to_lowercase(),
+            candidate_type: fields.get(7)?.to_lowercase(),
+        })
+    }
+}
+
+impl CandidateFilter {
+    pub fn is_empty(&self) -> bool {
+        self.drop.is_empty() && self.rewrite.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for rewrite in &self.rewrite {
+            if rewrite.from.is_ipv4() != rewrite.to.is_ipv4() {
+                bail!(
+                    "rewrite {} → {} changes address family",
+                    rewrite.from,
+                    rewrite.to
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Decide on one candidate line, "candidate:..." with or without "a=".
+    /// Lines that don't parse as a candidate are kept.
+    pub fn apply(&self, line: &str) -> Decision {
+        let Some(candidate) = Candidate::parse(line) else {
+            return Decision::Keep;
+        };
+
+        if let Some(drop) = self
+            .drop
+            .iter()
+            .find(|drop| drop.pattern.matches(&candidate))
+        {
+            return Decision::Drop {
+                pattern: drop.to_string(),
+            };
+        }
+
+        if candidate.candidate_type == "host" {
+            let rewrite = self
+                .rewrite
+                .iter()
+                .find(|rewrite| candidate.address.parse() == Ok(rewrite.from));
+            if let Some(AddressRewrite { from, to }) = rewrite {
+                return Decision::Rewrite {
+                    candidate: with_candidate_address(line, &to.to_string()),
+                    from: *from,
+                    to: *to,
+                };
+            }
+        }
+
+        Decision::Keep
+    }
+}
+
+/// Connection address of a candidate line, an IP or an mDNS name.
+pub fn candidate_address(line: &str) -> Option<&str> {
+    Candidate::parse(line)?;
+    line.split_whitespace().nth(4)
+}
+
+/// The candidate line with its connection address replaced, split into fields
+/// the same way as `candidate_address`.
+pub fn with_candidate_address(line: &str, address: &str) -> String {
+    line.split_whitespace()
+        .enumerate()
+        .map(|(i, field)| if i == 4 { address } else { field })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(address) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(address) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+// 169.254.0.0/16, fe80::/10
+fn is_link_local(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => address.is_link_local(),
+        IpAddr::V6(address) => address.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_V4: &str = "candidate:1 1 UDP 2122252543 172.17.0.2 50000 typ host";
+    const HOST_V6: &str = "candidate:2 1 UDP 2122187007 fe80::1c2b:3dff:fe4e:5f60 50001 typ host";
+    const MDNS: &str =
+        "a=candidate:3 1 udp 2122260223 6f1c1b1e-1d8b-4d6f-9e0e-2f1a3b4c5d6e.local 50002 typ host";
+    const SRFLX: &str =
+        "candidate:4 1 UDP 1686052607 203.0.113.7 50003 typ srflx raddr 172.17.0.2 rport 50000";
+    const LINK_LOCAL_V4: &str =
+        "candidate:5 1 TCP 1015021823 169.254.10.20 9 typ host tcptype active";
+
+    fn dropping(patterns: &[&str]) -> CandidateFilter {
+        CandidateFilter {
+            drop: patterns
+                .iter()
+                .map(|pattern| pattern.parse().unwrap())
+                .collect(),
+            rewrite: vec![],
+        }
+    }
+
+    fn dropped(filter: &CandidateFilter, line: &str) -> bool {
+        matches!(filter.apply(line), Decision::Drop { .. })
+    }
+
+    #[test]
+    fn drops_by_network() {
+        let filter = dropping(&["172.17.0.0/16"]);
+        assert!(dropped(&filter, HOST_V4));
+        assert!(!dropped(&filter, SRFLX));
+        assert!(!dropped(&filter, HOST_V6));
+
+        let filter = dropping(&["fe80::/10"]);
+        assert!(dropped(&filter, HOST_V6));
+        assert!(!dropped(&filter, HOST_V4));
+
+        // a bare address is a single host
+        let filter = dropping(&["172.17.0.3"]);
+        assert!(!dropped(&filter, HOST_V4));
+        let filter = dropping(&["172.17.0.2"]);
+        assert!(dropped(&filter, HOST_V4));
+
+        let filter = dropping(&["0.0.0.0/0"]);
+        assert!(dropped(&filter, HOST_V4));
+        assert!(dropped(&filter, SRFLX));
+        assert!(!dropped(&filter, HOST_V6));
+    }
+
+    #[test]
+    fn drops_mdns() {
+        let filter = dropping(&["mdns"]);
+        assert!(dropped(&filter, MDNS));
+        assert!(!dropped(&filter, HOST_V4));
+        assert_eq!(
+            filter.apply(MDNS),
+            Decision::Drop {
+                pattern: "mdns".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn drops_link_local() {
+        let filter = dropping(&["link-local"]);
+        assert!(dropped(&filter, HOST_V6));
+        assert!(dropped(&filter, LINK_LOCAL_V4));
+        assert!(!dropped(&filter, HOST_V4));
+        assert!(!dropped(&filter, MDNS));
+    }
+
+    #[test]
+    fn drops_by_transport_and_type() {
+        assert!(dropped(&dropping(&["tcp"]), LINK_LOCAL_V4));
+        assert!(!dropped(&dropping(&["tcp"]), HOST_V4));
+        assert!(dropped(&dropping(&["SRFLX"]), SRFLX));
+        assert!(!dropped(&dropping(&["relay"]), SRFLX));
+    }
+
+    #[test]
+    fn keeps_what_isnt_a_candidate() {
+        let filter = dropping(&["0.0.0.0/0", "ipv6", "mdns"]);
+        assert_eq!(filter.apply(""), Decision::Keep);
+        assert_eq!(filter.apply("a=end-of-candidates"), Decision::Keep);
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        for pattern in ["", "nope", "172.17.0.0/33", "fe80::/129", "10.0.0.0/x"] {
+            assert!(pattern.parse::<DropPattern>().is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn rewrites_host_addresses() {
+        let filter = CandidateFilter {
+            drop: vec![],
+            rewrite: vec![AddressRewrite {
+                from: "172.17.0.2".parse().unwrap(),
+                to: "198.51.100.1".parse().unwrap(),
+            }],
+        };
+
+        assert_eq!(
+            filter.apply(HOST_V4),
+            Decision::Rewrite {
+                candidate: "candidate:1 1 UDP 2122252543 198.51.100.1 50000 typ host".to_string(),
+                from: "172.17.0.2".parse().unwrap(),
+                to: "198.51.100.1".parse().unwrap(),
+            }
+        );
+        // only host candidates, srflx carries it as its related address
+        assert_eq!(filter.apply(SRFLX), Decision::Keep);
+    }
+
+    #[test]
+    fn drops_before_rewriting() {
+        let filter = CandidateFilter {
+            drop: vec!["host".parse().unwrap()],
+            rewrite: vec![AddressRewrite {
+                from: "172.17.0.2".parse().unwrap(),
+                to: "198.51.100.1".parse().unwrap(),
+            }],
+        };
+        assert!(dropped(&filter, HOST_V4));
+    }
+
+    #[test]
+    fn reads_and_replaces_the_same_field() {
+        let line = "candidate:1  1 UDP\t2122252543 172.17.0.2 50000 typ host";
+        assert_eq!(candidate_address(line), Some("172.17.0.2"));
+
+        let rewritten = with_candidate_address(line, "198.51.100.1");
+        assert_eq!(candidate_address(&rewritten), Some("198.51.100.1"));
+        assert_eq!(
+            rewritten,
+            "candidate:1 1 UDP 2122252543 198.51.100.1 50000 typ host"
+        );
+    }
+
+    #[test]
+    fn parses_patterns_from_config() {
+        let filter: CandidateFilter = toml::from_str(r#"drop = ["mdns", "10.0.0.0/8"]"#).unwrap();
+        assert_eq!(filter.drop.len(), 2);
+        assert_eq!(filter.drop[1].to_string(), "10.0.0.0/8");
+
+        assert!(toml::from_str::<CandidateFilter>(r#"drop = ["10.0.0.0/99"]"#).is_err());
+    }
+}
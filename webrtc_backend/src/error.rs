@@ -0,0 +1,64 @@
+use std::fmt;
+
+/// Something went wrong in a session. Reported to the application as a
+/// `SessionEvent::Error` and to the peer as an `error` signal with `code()`.
+#[derive(Debug)]
+pub enum SessionError {
+    /// The peer's offer/answer isn't valid SDP.
+    SdpParse(anyhow::Error),
+    /// Offer/answer exchange failed.
+    Negotiation(anyhow::Error),
+    /// An offer/answer arrived in a state that can't take it, e.g. an answer
+    /// we didn't ask for. It's ignored.
+    UnexpectedDescription(anyhow::Error),
+    /// ICE candidates, gathering or connectivity.
+    Ice(anyhow::Error),
+    /// Building or running the GStreamer pipeline.
+    Pipeline(anyhow::Error),
+    DataChannel(anyhow::Error),
+}
+
+pub type SessionResult<T> = std::result::Result<T, SessionError>;
+
+impl SessionError {
+    /// Sent to the peer in the `error` signal.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SessionError::SdpParse(_) => "sdp_parse",
+            SessionError::Negotiation(_) => "negotiation",
+            SessionError::UnexpectedDescription(_) => "unexpected_description",
+            SessionError::Ice(_) => "ice",
+            SessionError::Pipeline(_) => "pipeline",
+            SessionError::DataChannel(_) => "data_channel",
+        }
+    }
+
+    /// Whether the session has to end. A bad description is rejected and the
+    /// previous one stays in place, ICE has its own restart/fail handling and
+    /// a broken data channel doesn't affect media, so only a failed
+    /// negotiation or pipeline ends the session.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            SessionError::SdpParse(_)
+            | SessionError::UnexpectedDescription(_)
+            | SessionError::Ice(_)
+            | SessionError::DataChannel(_) => false,
+            SessionError::Negotiation(_) | SessionError::Pipeline(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::SdpParse(err) => write!(f, "SDP parse error: {err:#}"),
+            SessionError::Negotiation(err) => write!(f, "negotiation failed: {err:#}"),
+            SessionError::UnexpectedDescription(err) => write!(f, "{err:#}"),
+            SessionError::Ice(err) => write!(f, "ICE failed: {err:#}"),
+            SessionError::Pipeline(err) => write!(f, "pipeline error: {err:#}"),
+            SessionError::DataChannel(err) => write!(f, "data channel error: {err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
@@ -0,0 +1,145 @@
+use std::fmt;
+use tokio::sync::mpsc;
+
+use crate::session::SessionState;
+
+pub type SessionEventSender = mpsc::UnboundedSender<SessionEvent>;
+
+/// Something happened to a session, for the application to react to.
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    StateChanged {
+        session_id: u64,
+        state: SessionState,
+    },
+
+    ConnectionStateChanged {
+        session_id: u64,
+        state: gst_webrtc::WebRTCPeerConnectionState,
+    },
+    IceConnectionStateChanged {
+        session_id: u64,
+        state: gst_webrtc::WebRTCICEConnectionState,
+    },
+    IceGatheringStateChanged {
+        session_id: u64,
+        state: gst_webrtc::WebRTCICEGatheringState,
+    },
+    SignalingStateChanged {
+        session_id: u64,
+        state: gst_webrtc::WebRTCSignalingState,
+    },
+
+    /// m-line negotiated, `kind` is "audio", "video" or "application"
+    TrackAdded {
+        session_id: u64,
+        mid: String,
+        kind: String,
+    },
+    TrackRemoved {
+        session_id: u64,
+        mid: String,
+    },
+    /// Media from the peer started arriving, `codec` is the RTP encoding
+    /// name, e.g. "VP8"
+    TrackReceived {
+        session_id: u64,
+        mid: String,
+        kind: String,
+        codec: String,
+        caps: String,
+    },
+
+    DataChannelOpen {
+        session_id: u64,
+        label: String,
+    },
+    DataChannelClosed {
+        session_id: u64,
+        label: String,
+    },
+    DataChannelError {
+        session_id: u64,
+        label: String,
+        error_message: String,
+    },
+
+    /// `code` as sent to the peer, see `SessionError::code`. The session
+    /// ends after a `fatal` error.
+    Error {
+        session_id: u64,
+        code: &'static str,
+        error_message: String,
+        fatal: bool,
+    },
+
+    /// Session stopped, no more events will follow for it.
+    Ended {
+        session_id: u64,
+    },
+}
+
+impl fmt::Display for SessionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionEvent::StateChanged { session_id, state } => {
+                write!(f, "session {session_id} {state:?}")
+            }
+            SessionEvent::ConnectionStateChanged { session_id, state } => {
+                write!(f, "session {session_id} connection {state:?}")
+            }
+            SessionEvent::IceConnectionStateChanged { session_id, state } => {
+                write!(f, "session {session_id} ICE connection {state:?}")
+            }
+            SessionEvent::IceGatheringStateChanged { session_id, state } => {
+                write!(f, "session {session_id} ICE gathering {state:?}")
+            }
+            SessionEvent::SignalingStateChanged { session_id, state } => {
+                write!(f, "session {session_id} signaling {state:?}")
+            }
+            SessionEvent::TrackAdded {
+                session_id,
+                mid,
+                kind,
+            } => write!(f, "session {session_id} track {mid} added ({kind})"),
+            SessionEvent::TrackRemoved { session_id, mid } => {
+                write!(f, "session {session_id} track {mid} removed")
+            }
+            SessionEvent::TrackReceived {
+                session_id,
+                mid,
+                kind,
+                codec,
+                caps,
+            } => write!(
+                f,
+                "session {session_id} track {mid} receiving {kind} {codec} ({caps})"
+            ),
+            SessionEvent::DataChannelOpen { session_id, label } => {
+                write!(f, "session {session_id} data channel {label:?} open")
+            }
+            SessionEvent::DataChannelClosed { session_id, label } => {
+                write!(f, "session {session_id} data channel {label:?} closed")
+            }
+            SessionEvent::DataChannelError {
+                session_id,
+                label,
+                error_message,
+            } => write!(
+                f,
+                "session {session_id} data channel {label:?} error: {error_message}"
+            ),
+            SessionEvent::Error {
+                session_id,
+                code,
+                error_message,
+                fatal,
+            } => write!(
+                f,
+                "session {session_id} {}error {code}: {error_message}",
+                if *fatal { "fatal " } else { "" }
+            ),
+            SessionEvent::Ended { session_id } => write!(f, "session {session_id} ended"),
+        }
+    }
+}
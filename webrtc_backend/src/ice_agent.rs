@@ -0,0 +1,106 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// Settings for the libnice agent behind webrtcbin ("ice-agent"), for
+/// reproducing a customer's network setup.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct IceAgentOptions {
+    /// Gather TCP candidates.
+    pub tcp: bool,
+
+    /// Gather UDP candidates.
+    pub udp: bool,
+
+    /// Local port range for host candidates, 0-65535 for any.
+    pub min_port: u16,
+    pub max_port: u16,
+
+    /// Only gather on these network interfaces (e.g. "eth0"), all when empty.
+    pub interfaces: Vec<String>,
+
+    /// "ipv4" and/or "ipv6", both when empty.
+    pub address_families: Vec<String>,
+}
+
+impl Default for IceAgentOptions {
+    fn default() -> Self {
+        // the agent's own defaults
+        Self {
+            tcp: true,
+            udp: true,
+            min_port: 0,
+            max_port: 65535,
+            interfaces: vec![],
+            address_families: vec![],
+        }
+    }
+}
+
+const ADDRESS_FAMILIES: &[&str] = &["ipv4", "ipv6"];
+
+impl IceAgentOptions {
+    pub fn validate(&self) -> Result<()> {
+        if !self.tcp && !self.udp {
+            bail!("tcp and udp are both disabled, there would be no candidates");
+        }
+        if self.min_port > self.max_port {
+            bail!(
+                "min-port {} is above max-port {}",
+                self.min_port,
+                self.max_port
+            );
+        }
+        for family in &self.address_families {
+            if !ADDRESS_FAMILIES.contains(&family.as_str()) {
+                bail!(
+                    "address family {family:?} should be one of {}",
+                    ADDRESS_FAMILIES.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Addresses to gather on, for the agent's "add-local-ip-address".
+    /// None to let the agent discover them, when nothing is restricted.
+    pub fn local_addresses(&self) -> Result<Option<Vec<IpAddr>>> {
+        if self.interfaces.is_empty() && self.address_families.is_empty() {
+            return Ok(None);
+        }
+
+        let addresses: Vec<IpAddr> = if_addrs::get_if_addrs()
+            .context("failed to list network interfaces")?
+            .into_iter()
+            .filter(|interface| {
+                if self.interfaces.is_empty() {
+                    // like the agent's own discovery
+                    !interface.is_loopback()
+                } else {
+                    self.interfaces.contains(&interface.name)
+                }
+            })
+            .map(|interface| interface.ip())
+            .filter(|ip| self.allows_family(ip))
+            .collect();
+
+        if addresses.is_empty() {
+            bail!(
+                "no {:?} addresses on interfaces {:?}",
+                self.address_families,
+                self.interfaces
+            );
+        }
+
+        Ok(Some(addresses))
+    }
+
+    fn allows_family(&self, ip: &IpAddr) -> bool {
+        let family = match ip {
+            IpAddr::V4(_) => "ipv4",
+            IpAddr::V6(_) => "ipv6",
+        };
+        self.address_families.is_empty() || self.address_families.iter().any(|f| f == family)
+    }
+}
@@ -1,28 +1,85 @@
 use anyhow::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
 use tokio::sync::mpsc;
 
+mod candidate_filter;
+mod config;
+mod error;
+mod events;
+mod ice_agent;
+mod ice_servers;
+mod mdns;
+mod media;
 mod session;
 mod signals;
 mod webrtc;
 
+use config::Config;
+use events::SessionEvent;
+use media::SampleCallback;
 use signals::Signal;
 use webrtc::WebRtc;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     println!("Minimal GStreamer WebRTC - Backend");
 
+    let config = Config::from_args()?;
+    println!("{config:#?}");
+
+    // before gst::init, so plugins come from the same install as the libraries,
+    // and before the runtime starts, as setting it with other threads around isn't safe
+    if let Some(plugin_dir) = config.gstreamer_plugin_dir() {
+        std::env::set_var("GST_PLUGIN_SYSTEM_PATH", plugin_dir);
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(run(config))
+}
+
+async fn run(config: Config) -> Result<()> {
     let (incoming_signals_tx, mut incoming_signals_rx) = mpsc::unbounded_channel::<Signal>();
     let (outgoing_signals_tx, outgoing_signals_rx) = mpsc::unbounded_channel::<Signal>();
+    let (session_events_tx, mut session_events_rx) = mpsc::unbounded_channel::<SessionEvent>();
 
-    signals::connect(
-        "ws://127.0.0.1:10001",
-        incoming_signals_tx,
-        outgoing_signals_rx,
-    )
-    .await;
+    let signals_task =
+        signals::connect(&config.signal_url, incoming_signals_tx, outgoing_signals_rx).await?;
 
-    let webrtc = WebRtc::new(outgoing_signals_tx).await?;
+    let mdns_responder_names = config.mdns_responder_names()?;
+    if !mdns_responder_names.is_empty() {
+        tokio::spawn(async move {
+            let address = mdns::LOOPBACK_RESPONDER_ADDRESS;
+            if let Err(err) = mdns::respond(address, mdns_responder_names).await {
+                println!("[mDNS] responder failed: {err:#}");
+            }
+        });
+    }
+
+    let mut session_options = config.session_options();
+
+    // media.incoming "app" sinks end up here, show every so many samples
+    let samples = AtomicU64::new(0);
+    session_options.sample_callback = Some(SampleCallback(Arc::new(move |track, sample| {
+        let count = samples.fetch_add(1, Ordering::Relaxed);
+        if count % 100 != 0 {
+            return;
+        }
+        if let Some(buffer) = sample.buffer() {
+            println!(
+                "[App] sample {count}: session {} mid {} {}, {} bytes at {}",
+                track.session_id,
+                track.mid,
+                track.kind,
+                buffer.size(),
+                buffer.pts().display()
+            );
+        }
+    })));
+
+    let webrtc = WebRtc::new(outgoing_signals_tx, session_events_tx, session_options).await?;
+
+    let mut sigterm = unix_signal(SignalKind::terminate())?;
 
     loop {
         tokio::select! {
@@ -30,9 +87,30 @@ async fn main() -> Result<()> {
                 println!("SIGNAL {signal:?}");
                 webrtc.on_incoming_signal(signal).await;
             }
+            Some(event) = session_events_rx.recv() => {
+                println!("SESSION EVENT {event}");
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!("SIGINT, shutting down");
+                break;
+            }
+            _ = sigterm.recv() => {
+                println!("SIGTERM, shutting down");
+                break;
+            }
         }
     }
 
-    #[allow(unreachable_code)]
+    webrtc.shutdown().await;
+
+    // last signal sender goes with it, letting the signals task send the byes and close
+    drop(webrtc);
+    if tokio::time::timeout(Duration::from_secs(2), signals_task)
+        .await
+        .is_err()
+    {
+        println!("signals not flushed, exiting anyway");
+    }
+
     Ok(())
 }
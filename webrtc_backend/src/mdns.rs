@@ -0,0 +1,380 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// Minimal mDNS (RFC 6762): one-shot A/AAAA queries for the "<uuid>.local"
+// host candidates browsers send, and a responder to test them on loopback.
+
+/// Where mDNS queries normally go.
+pub const MDNS_ADDRESS: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+/// Where --mdns-responder answers, away from any system responder on 5353.
+pub const LOOPBACK_RESPONDER_ADDRESS: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 15353);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// in a question: answer me directly, in an answer: replaces cached records
+const UNICAST_RESPONSE: u16 = 0x8000;
+const CACHE_FLUSH: u16 = 0x8000;
+const RESPONSE_TTL: u32 = 120;
+
+/// Resolve `name` (e.g. "1f4712db-ea17-4bcf-a596-105139dfd8bf.local") by asking
+/// `server`, the mDNS multicast group or a loopback responder.
+///
+/// Queries come from an ephemeral port, so responders answer us directly
+/// (a "one-shot" query) rather than to the multicast group.
+pub async fn resolve(name: &str, server: SocketAddr, timeout: Duration) -> Result<IpAddr> {
+    let bind_address = match server {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind_address)
+        .await
+        .context("failed to bind mDNS socket")?;
+
+    let id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |now| now.subsec_nanos() as u16);
+    socket
+        .send_to(&query(id, name), server)
+        .await
+        .with_context(|| format!("failed to send mDNS query to {server}"))?;
+
+    let answer = tokio::time::timeout(timeout, async {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, _from) = socket.recv_from(&mut buf).await?;
+            if let Some(address) = answer_address(&buf[..len], name) {
+                return Ok::<_, std::io::Error>(address);
+            }
+        }
+    })
+    .await;
+
+    match answer {
+        Ok(Ok(address)) => Ok(address),
+        Ok(Err(err)) => Err(anyhow!("mDNS query for {name} failed: {err}")),
+        Err(_) => bail!("no mDNS answer for {name} from {server} within {timeout:?}"),
+    }
+}
+
+/// Answer A/AAAA queries for `names` ("*" for any name) on `address`, until
+/// the socket fails. For testing resolution on loopback, see --mdns-responder.
+pub async fn respond(address: SocketAddr, names: HashMap<String, IpAddr>) -> Result<()> {
+    let socket = UdpSocket::bind(address)
+        .await
+        .with_context(|| format!("failed to bind mDNS responder to {address}"))?;
+    println!("[mDNS] responder on {address} for {names:?}");
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Some((id, questions)) = parse_query(&buf[..len]) else {
+            continue;
+        };
+
+        let answers: Vec<(String, IpAddr)> = questions
+            .iter()
+            .filter_map(|(name, record_type)| {
+                let address = names.get(&name.to_lowercase()).or_else(|| names.get("*"))?;
+                let wanted = match address {
+                    IpAddr::V4(_) => TYPE_A,
+                    IpAddr::V6(_) => TYPE_AAAA,
+                };
+                (*record_type == wanted || *record_type == TYPE_ANY)
+                    .then(|| (name.clone(), *address))
+            })
+            .collect();
+        if answers.is_empty() {
+            continue;
+        }
+
+        for (name, address) in &answers {
+            println!("[mDNS] {from} asked for {name}, answering {address}");
+        }
+        socket
+            .send_to(&response(id, &questions, &answers), from)
+            .await?;
+    }
+}
+
+// one A and one AAAA question for `name`
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut data = header(id, 0x0000, 2, 0);
+    for record_type in [TYPE_A, TYPE_AAAA] {
+        push_name(&mut data, name);
+        data.extend_from_slice(&record_type.to_be_bytes());
+        data.extend_from_slice(&(CLASS_IN | UNICAST_RESPONSE).to_be_bytes());
+    }
+    data
+}
+
+// authoritative answer, echoing the id and questions as one-shot queriers expect
+fn response(id: u16, questions: &[(String, u16)], answers: &[(String, IpAddr)]) -> Vec<u8> {
+    let mut data = header(id, 0x8400, questions.len() as u16, answers.len() as u16);
+    for (name, record_type) in questions {
+        push_name(&mut data, name);
+        data.extend_from_slice(&record_type.to_be_bytes());
+        data.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for (name, address) in answers {
+        let (record_type, rdata) = match address {
+            IpAddr::V4(address) => (TYPE_A, address.octets().to_vec()),
+            IpAddr::V6(address) => (TYPE_AAAA, address.octets().to_vec()),
+        };
+        push_name(&mut data, name);
+        data.extend_from_slice(&record_type.to_be_bytes());
+        data.extend_from_slice(&(CLASS_IN | CACHE_FLUSH).to_be_bytes());
+        data.extend_from_slice(&RESPONSE_TTL.to_be_bytes());
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend_from_slice(&rdata);
+    }
+    data
+}
+
+fn header(id: u16, flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut data = Vec::with_capacity(512);
+    for field in [id, flags, questions, answers, 0, 0] {
+        data.extend_from_slice(&field.to_be_bytes());
+    }
+    data
+}
+
+fn push_name(data: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+    data.push(0);
+}
+
+// (id, [(name, type)]) of a query
+fn parse_query(data: &[u8]) -> Option<(u16, Vec<(String, u16)>)> {
+    let flags = read_u16(data, 2)?;
+    if flags & 0x8000 != 0 {
+        // a response
+        return None;
+    }
+
+    let mut offset = 12;
+    let mut questions = vec![];
+    for _ in 0..read_u16(data, 4)? {
+        let (name, next) = read_name(data, offset)?;
+        // type then class, both there or the question's cut short
+        read_u16(data, next + 2)?;
+        questions.push((name, read_u16(data, next)?));
+        offset = next + 4;
+    }
+    Some((read_u16(data, 0)?, questions))
+}
+
+// first A/AAAA record for `name` in a response
+fn answer_address(data: &[u8], name: &str) -> Option<IpAddr> {
+    let flags = read_u16(data, 2)?;
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+
+    let mut offset = 12;
+    for _ in 0..read_u16(data, 4)? {
+        offset = read_name(data, offset)?.1 + 4;
+    }
+
+    // answers, then authority and additional records, which may carry it too
+    let records =
+        read_u16(data, 6)? as usize + read_u16(data, 8)? as usize + read_u16(data, 10)? as usize;
+    for _ in 0..records {
+        let (record_name, next) = read_name(data, offset)?;
+        let record_type = read_u16(data, next)?;
+        let length = read_u16(data, next + 8)? as usize;
+        let rdata = data.get(next + 10..next + 10 + length)?;
+        offset = next + 10 + length;
+
+        if !record_name.eq_ignore_ascii_case(name.trim_end_matches('.')) {
+            continue;
+        }
+        match (record_type, rdata.len()) {
+            (TYPE_A, 4) => return Some(IpAddr::from(<[u8; 4]>::try_from(rdata).ok()?)),
+            (TYPE_AAAA, 16) => return Some(IpAddr::from(<[u8; 16]>::try_from(rdata).ok()?)),
+            _ => {}
+        }
+    }
+    None
+}
+
+// dotted name at `offset` following compression pointers, and the offset after it
+fn read_name(data: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut position = offset;
+    let mut end = None;
+    // bounds pointer loops
+    for _ in 0..128 {
+        let length = *data.get(position)? as usize;
+        match length {
+            0 => {
+                let end = end.unwrap_or(position + 1);
+                return Some((labels.join("."), end));
+            }
+            length if length & 0xc0 == 0xc0 => {
+                let pointer = read_u16(data, position)? as usize & 0x3fff;
+                end.get_or_insert(position + 2);
+                position = pointer;
+            }
+            length => {
+                let label = data.get(position + 1..position + 1 + length)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                position += 1 + length;
+            }
+        }
+    }
+    None
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *data.get(offset)?,
+        *data.get(offset + 1)?,
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "1f4712db-ea17-4bcf-a596-105139dfd8bf.local";
+
+    // a loopback port nothing is listening on, for a responder
+    fn free_address() -> SocketAddr {
+        std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|socket| socket.local_addr())
+            .expect("failed to find a free port")
+    }
+
+    #[tokio::test]
+    async fn resolves_through_responder() {
+        let address = free_address();
+        let names = HashMap::from([
+            (NAME.to_string(), IpAddr::from([192, 168, 1, 20])),
+            ("*".to_string(), IpAddr::from(Ipv6Addr::LOCALHOST)),
+        ]);
+        let responder = tokio::spawn(respond(address, names));
+        // let it bind
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let timeout = Duration::from_secs(1);
+        let resolved = resolve(&NAME.to_uppercase(), address, timeout).await;
+        assert_eq!(resolved.unwrap(), IpAddr::from([192, 168, 1, 20]));
+
+        let resolved = resolve("other.local", address, timeout).await;
+        assert_eq!(resolved.unwrap(), IpAddr::from(Ipv6Addr::LOCALHOST));
+
+        responder.abort();
+    }
+
+    #[tokio::test]
+    async fn unanswered_query_times_out() {
+        let address = free_address();
+        let names = HashMap::from([(NAME.to_string(), IpAddr::from([192, 168, 1, 20]))]);
+        let responder = tokio::spawn(respond(address, names));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let resolved = resolve("other.local", address, Duration::from_millis(200)).await;
+        assert!(resolved.is_err());
+
+        responder.abort();
+    }
+
+    #[test]
+    fn parses_query() {
+        let (id, questions) = parse_query(&query(0x1234, NAME)).unwrap();
+        assert_eq!(id, 0x1234);
+        assert_eq!(
+            questions,
+            vec![(NAME.to_string(), TYPE_A), (NAME.to_string(), TYPE_AAAA)]
+        );
+    }
+
+    #[test]
+    fn reads_answer() {
+        let questions = [(NAME.to_string(), TYPE_A)];
+        let answers = [(NAME.to_string(), IpAddr::from([10, 0, 0, 1]))];
+        let data = response(7, &questions, &answers);
+
+        assert_eq!(
+            answer_address(&data, &NAME.to_uppercase()),
+            Some(IpAddr::from([10, 0, 0, 1]))
+        );
+        assert_eq!(answer_address(&data, "other.local"), None);
+        // queries and responses aren't mistaken for each other
+        assert_eq!(parse_query(&data), None);
+        assert_eq!(answer_address(&query(7, NAME), NAME), None);
+    }
+
+    #[test]
+    fn reads_compressed_answer_name() {
+        let mut data = header(7, 0x8400, 1, 1);
+        push_name(&mut data, NAME);
+        data.extend_from_slice(&TYPE_AAAA.to_be_bytes());
+        data.extend_from_slice(&CLASS_IN.to_be_bytes());
+        // the answer's name points back at the question's, at 12
+        data.extend_from_slice(&[0xc0, 12]);
+        data.extend_from_slice(&TYPE_AAAA.to_be_bytes());
+        data.extend_from_slice(&CLASS_IN.to_be_bytes());
+        data.extend_from_slice(&RESPONSE_TTL.to_be_bytes());
+        data.extend_from_slice(&16u16.to_be_bytes());
+        data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+
+        assert_eq!(
+            answer_address(&data, NAME),
+            Some(IpAddr::from(Ipv6Addr::LOCALHOST))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let query = query(7, NAME);
+        let response = response(
+            7,
+            &[(NAME.to_string(), TYPE_A)],
+            &[(NAME.to_string(), IpAddr::from([10, 0, 0, 1]))],
+        );
+
+        for len in 0..query.len() {
+            assert_eq!(parse_query(&query[..len]), None, "query cut to {len}");
+        }
+        for len in 0..response.len() {
+            assert_eq!(
+                answer_address(&response[..len], NAME),
+                None,
+                "response cut to {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        // more questions than there are
+        let mut data = query(7, NAME);
+        data[5] = 3;
+        assert_eq!(parse_query(&data), None);
+
+        // a name pointing at itself
+        let mut data = header(7, 0x0000, 1, 0);
+        data.extend_from_slice(&[0xc0, 12]);
+        data.extend_from_slice(&TYPE_A.to_be_bytes());
+        data.extend_from_slice(&CLASS_IN.to_be_bytes());
+        assert_eq!(parse_query(&data), None);
+
+        // an answer longer than the packet
+        let mut data = response(7, &[], &[(NAME.to_string(), IpAddr::from([10, 0, 0, 1]))]);
+        let len = data.len();
+        data[len - 5] = 5;
+        assert_eq!(answer_address(&data, NAME), None);
+    }
+}
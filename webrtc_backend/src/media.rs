@@ -0,0 +1,1014 @@
+use anyhow::{anyhow, bail, Context, Result};
+use gst::prelude::*;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+/// Test video we send the peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoSendOptions {
+    /// videotestsrc "pattern", e.g. "smpte" or "ball".
+    pub pattern: String,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+    /// Encoder target, kbit/s.
+    pub bitrate: u32,
+    /// "VP8", "VP9", "H264" or "AV1".
+    pub codec: String,
+    /// One of `H264_PROFILES`, for H264.
+    pub h264_profile: String,
+    /// "sendrecv", or "sendonly" to ignore any video from the peer.
+    pub direction: String,
+}
+
+impl Default for VideoSendOptions {
+    fn default() -> Self {
+        Self {
+            pattern: "smpte".to_string(),
+            width: 640,
+            height: 480,
+            framerate: 30,
+            bitrate: 1000,
+            codec: "VP8".to_string(),
+            h264_profile: "constrained-baseline".to_string(),
+            direction: "sendrecv".to_string(),
+        }
+    }
+}
+
+/// Test audio we send the peer, always Opus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSendOptions {
+    /// audiotestsrc "wave", e.g. "sine" or "ticks".
+    pub wave: String,
+    /// Hz.
+    pub frequency: f64,
+    /// 1 or 2.
+    pub channels: u32,
+    /// Opus bitrate, kbit/s.
+    pub bitrate: u32,
+    /// Opus in-band forward error correction.
+    pub fec: bool,
+    /// Opus discontinuous transmission, next to nothing is sent for silence.
+    pub dtx: bool,
+    /// Opus frame size, ms.
+    pub frame_size: u32,
+    /// "sendrecv", or "sendonly" to ignore any audio from the peer.
+    pub direction: String,
+}
+
+impl Default for AudioSendOptions {
+    fn default() -> Self {
+        Self {
+            wave: "sine".to_string(),
+            frequency: 440.0,
+            channels: 1,
+            bitrate: 32,
+            fec: true,
+            dtx: false,
+            frame_size: 20,
+            direction: "sendrecv".to_string(),
+        }
+    }
+}
+
+pub const VIDEO_CODECS: &[&str] = &["VP8", "VP9", "H264", "AV1"];
+pub const AUDIO_CODECS: &[&str] = &["OPUS"];
+/// For "H264/<profile>" codec preferences, and what x264enc then encodes.
+pub const H264_PROFILES: &[&str] = &["constrained-baseline", "baseline", "main", "high"];
+pub const VIDEO_PATTERNS: &[&str] = &[
+    "smpte",
+    "snow",
+    "black",
+    "white",
+    "red",
+    "green",
+    "blue",
+    "checkers-1",
+    "checkers-2",
+    "checkers-4",
+    "checkers-8",
+    "circular",
+    "blink",
+    "smpte75",
+    "zone-plate",
+    "gamut",
+    "chroma-zone-plate",
+    "solid-color",
+    "ball",
+    "smpte100",
+    "bar",
+    "pinwheel",
+    "spokes",
+    "gradient",
+    "colors",
+];
+pub const AUDIO_WAVES: &[&str] = &[
+    "sine",
+    "square",
+    "saw",
+    "triangle",
+    "silence",
+    "white-noise",
+    "pink-noise",
+    "sine-table",
+    "ticks",
+    "gaussian-noise",
+    "red-noise",
+    "blue-noise",
+    "violet-noise",
+];
+/// opusenc "frame-size", ms.
+pub const OPUS_FRAME_SIZES: &[u32] = &[5, 10, 20, 40, 60];
+pub const SEND_DIRECTIONS: &[&str] = &["sendrecv", "sendonly"];
+pub const INCOMING_SINKS: &[&str] = &["stats", "file", "app"];
+
+/// Where decoded media from the peer goes, per media kind.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IncomingSink {
+    /// fakesink, logging packets, bytes and decoded frames now and then.
+    Stats,
+    /// y4m video or wav audio, "session-<id>-<mid>" in this directory.
+    File(PathBuf),
+    /// appsink, each sample handed to `SessionOptions::sample_callback`.
+    App,
+}
+
+/// Called from a streaming thread with every decoded sample of an "app" sink.
+#[derive(Clone)]
+pub struct SampleCallback(pub Arc<dyn Fn(&IncomingTrack, &gst::Sample) + Send + Sync>);
+
+impl fmt::Debug for SampleCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SampleCallback")
+    }
+}
+
+/// RTP arriving from the peer on a webrtcbin src pad.
+#[derive(Debug, Clone)]
+pub struct IncomingTrack {
+    pub session_id: u64,
+    /// "?" when webrtcbin doesn't know the transceiver yet.
+    pub mid: String,
+    /// "audio" or "video".
+    pub kind: String,
+    /// RTP encoding name, e.g. "VP8" or "OPUS".
+    pub codec: String,
+    pub caps: gst::Caps,
+}
+
+impl IncomingTrack {
+    pub fn from_pad(session_id: u64, pad: &gst::Pad) -> Result<Self> {
+        let caps = pad.current_caps().unwrap_or_else(|| pad.query_caps(None));
+        let structure = caps
+            .structure(0)
+            .ok_or_else(|| anyhow!("{} has no caps", pad.name()))?;
+        if structure.name() != "application/x-rtp" {
+            bail!("{} isn't RTP: {caps}", pad.name());
+        }
+        let kind = structure
+            .get::<String>("media")
+            .with_context(|| format!("no media kind in {caps}"))?;
+        let codec = structure
+            .get::<String>("encoding-name")
+            .with_context(|| format!("no encoding-name in {caps}"))?;
+        let mid = pad
+            .property::<Option<gst_webrtc::WebRTCRTPTransceiver>>("transceiver")
+            .and_then(|transceiver| transceiver.property::<Option<String>>("mid"))
+            .unwrap_or_else(|| "?".to_string());
+
+        Ok(Self {
+            session_id,
+            mid,
+            kind,
+            codec: codec.to_uppercase(),
+            caps,
+        })
+    }
+}
+
+// dynamic payload types for our m-lines
+const VIDEO_PAYLOAD_TYPE: u32 = 96;
+const AUDIO_PAYLOAD_TYPE: u32 = 111;
+
+// depayloader and parser that get a codec from RTP into matroskamux as it was sent
+const RECORDING_PASSTHROUGH: &[(&str, &[&str])] = &[
+    ("VP8", &["rtpvp8depay"]),
+    ("VP9", &["rtpvp9depay"]),
+    ("H264", &["rtph264depay", "h264parse"]),
+    ("AV1", &["rtpav1depay", "av1parse"]),
+    ("OPUS", &["rtpopusdepay", "opusparse"]),
+];
+
+// profile_idc and constraint flags opening an H264 "profile-level-id", per
+// profile; 640c is constrained high, which Safari offers
+const H264_PROFILE_IDS: &[(&str, &[&str])] = &[
+    ("constrained-baseline", &["42e0", "42c0"]),
+    ("baseline", &["4200"]),
+    ("main", &["4d00"]),
+    ("high", &["6400", "640c"]),
+];
+// levels 1 to 5.2 for the end of a "profile-level-id"
+const H264_LEVELS: &[&str] = &[
+    "0a", "0b", "0c", "0d", "14", "15", "16", "1e", "1f", "20", "28", "29", "2a", "32", "33", "34",
+];
+
+// how often a "stats" sink logs
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Opus always runs at 48 kHz
+const OPUS_RATE: i32 = 48_000;
+// how much loss in-band FEC is tuned for
+const OPUS_FEC_PACKET_LOSS_PERCENTAGE: i32 = 10;
+
+/// videotestsrc → encoder → payloader into a new webrtcbin sink pad, whose
+/// transceiver gets `options.direction`.
+pub fn add_video_send(
+    session_id: u64,
+    pipeline: &gst::Pipeline,
+    webrtcbin: &gst::Element,
+    options: &VideoSendOptions,
+) -> Result<()> {
+    println!(
+        "[WebRTC Session {session_id}] VIDEO SEND {} {}x{}@{} {} kbit/s {} ({})",
+        options.pattern,
+        options.width,
+        options.height,
+        options.framerate,
+        options.bitrate,
+        options.codec,
+        options.direction
+    );
+
+    let source = gst::ElementFactory::make("videotestsrc")
+        .property("is-live", true)
+        .property_from_str("pattern", &options.pattern)
+        .build()?;
+    let convert = gst::ElementFactory::make("videoconvert").build()?;
+    let raw_caps = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("width", options.width as i32)
+                .field("height", options.height as i32)
+                .field("framerate", gst::Fraction::new(options.framerate as i32, 1))
+                .build(),
+        )
+        .build()?;
+    let queue = gst::ElementFactory::make("queue").build()?;
+
+    let mut elements = vec![source, convert, raw_caps, queue];
+    elements.extend(video_encoder(options)?);
+    elements.push(
+        gst::ElementFactory::make("capsfilter")
+            .property(
+                "caps",
+                gst::Caps::builder("application/x-rtp")
+                    .field("media", "video")
+                    .field("encoding-name", options.codec.as_str())
+                    .field("payload", VIDEO_PAYLOAD_TYPE as i32)
+                    .build(),
+            )
+            .build()?,
+    );
+
+    add_send_branch(pipeline, webrtcbin, &elements, &options.direction)
+}
+
+/// audiotestsrc → opusenc → rtpopuspay into a new webrtcbin sink pad, whose
+/// transceiver gets `options.direction`.
+pub fn add_audio_send(
+    session_id: u64,
+    pipeline: &gst::Pipeline,
+    webrtcbin: &gst::Element,
+    options: &AudioSendOptions,
+) -> Result<()> {
+    println!(
+        "[WebRTC Session {session_id}] AUDIO SEND {} {} Hz {} ch, Opus {} kbit/s fec {} dtx {} {} ms ({})",
+        options.wave,
+        options.frequency,
+        options.channels,
+        options.bitrate,
+        options.fec,
+        options.dtx,
+        options.frame_size,
+        options.direction
+    );
+
+    let source = gst::ElementFactory::make("audiotestsrc")
+        .property("is-live", true)
+        .property_from_str("wave", &options.wave)
+        .property("freq", options.frequency)
+        .build()?;
+    let convert = gst::ElementFactory::make("audioconvert").build()?;
+    let resample = gst::ElementFactory::make("audioresample").build()?;
+    let raw_caps = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("audio/x-raw")
+                .field("rate", OPUS_RATE)
+                .field("channels", options.channels as i32)
+                .build(),
+        )
+        .build()?;
+    let queue = gst::ElementFactory::make("queue").build()?;
+
+    let encoder = gst::ElementFactory::make("opusenc")
+        .property("bitrate", (options.bitrate * 1000) as i32)
+        .property("inband-fec", options.fec)
+        .property("dtx", options.dtx)
+        .property_from_str("frame-size", &options.frame_size.to_string())
+        .build()?;
+    if options.fec {
+        // FEC only kicks in when the encoder expects loss
+        encoder.set_property("packet-loss-percentage", OPUS_FEC_PACKET_LOSS_PERCENTAGE);
+    }
+    let payloader = gst::ElementFactory::make("rtpopuspay")
+        // tells the browser DTX gaps are intentional
+        .property("dtx", options.dtx)
+        .build()?;
+    let rtp_caps = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("application/x-rtp")
+                .field("media", "audio")
+                .field("encoding-name", "OPUS")
+                .field("payload", AUDIO_PAYLOAD_TYPE as i32)
+                .build(),
+        )
+        .build()?;
+
+    let elements = [
+        source, convert, resample, raw_caps, queue, encoder, payloader, rtp_caps,
+    ];
+    add_send_branch(pipeline, webrtcbin, &elements, &options.direction)
+}
+
+// encoder, any caps it needs, payloader
+fn video_encoder(options: &VideoSendOptions) -> Result<Vec<gst::Element>> {
+    let bitrate = options.bitrate;
+    // a keyframe every 2 s, so a late or lossy receiver recovers quickly
+    let keyframe_interval = options.framerate * 2;
+
+    Ok(match options.codec.as_str() {
+        "VP8" | "VP9" => {
+            let (encoder, payloader) = match options.codec.as_str() {
+                "VP8" => ("vp8enc", "rtpvp8pay"),
+                _ => ("vp9enc", "rtpvp9pay"),
+            };
+            vec![
+                gst::ElementFactory::make(encoder)
+                    // realtime
+                    .property("deadline", 1i64)
+                    .property("target-bitrate", (bitrate * 1000) as i32)
+                    .property("keyframe-max-dist", keyframe_interval as i32)
+                    .build()?,
+                gst::ElementFactory::make(payloader).build()?,
+            ]
+        }
+        "H264" => vec![
+            gst::ElementFactory::make("x264enc")
+                .property_from_str("tune", "zerolatency")
+                .property_from_str("speed-preset", "ultrafast")
+                .property("bitrate", bitrate)
+                .property("key-int-max", keyframe_interval)
+                .build()?,
+            // constrained-baseline by default, what every browser decodes
+            gst::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gst::Caps::builder("video/x-h264")
+                        .field("profile", options.h264_profile.as_str())
+                        .build(),
+                )
+                .build()?,
+            gst::ElementFactory::make("rtph264pay")
+                // SPS/PPS with every keyframe
+                .property("config-interval", -1i32)
+                .property_from_str("aggregate-mode", "zero-latency")
+                .build()?,
+        ],
+        "AV1" => vec![
+            gst::ElementFactory::make("av1enc")
+                .property_from_str("usage-profile", "realtime")
+                .property("cpu-used", 8i32)
+                .property("target-bitrate", bitrate)
+                // guint here, unlike vpxenc's gint
+                .property("keyframe-max-dist", keyframe_interval)
+                .build()?,
+            gst::ElementFactory::make("rtpav1pay").build()?,
+        ],
+        other => bail!("no encoder for video codec {other}"),
+    })
+}
+
+// link `elements` into a new webrtcbin sink pad and set its transceiver direction
+fn add_send_branch(
+    pipeline: &gst::Pipeline,
+    webrtcbin: &gst::Element,
+    elements: &[gst::Element],
+    direction: &str,
+) -> Result<()> {
+    pipeline.add_many(elements)?;
+    gst::Element::link_many(elements)?;
+
+    let last = elements.last().expect("send branch has elements");
+    let sink_pad = webrtcbin
+        .request_pad_simple("sink_%u")
+        .ok_or_else(|| anyhow!("webrtcbin gave no sink pad"))?;
+    last.static_pad("src")
+        .expect("send branch ends in an element with a src pad")
+        .link(&sink_pad)
+        .with_context(|| format!("failed to link {} to webrtcbin", last.name()))?;
+
+    let transceiver = sink_pad.property::<gst_webrtc::WebRTCRTPTransceiver>("transceiver");
+    let direction = match direction {
+        "sendonly" => gst_webrtc::WebRTCRTPTransceiverDirection::Sendonly,
+        _ => gst_webrtc::WebRTCRTPTransceiverDirection::Sendrecv,
+    };
+    transceiver.set_property("direction", direction);
+
+    for element in elements {
+        element.sync_state_with_parent()?;
+    }
+    Ok(())
+}
+
+/// Check a codec preference: one of `VIDEO_CODECS` or `AUDIO_CODECS`, in any
+/// case, or "H264/<profile>" with one of `H264_PROFILES`.
+pub fn validate_codec(codec: &str) -> Result<()> {
+    let (name, profile) = split_codec(codec);
+    if !VIDEO_CODECS.contains(&name.as_str()) && !AUDIO_CODECS.contains(&name.as_str()) {
+        bail!(
+            "codec {codec:?} should be one of {}, {}",
+            VIDEO_CODECS.join(", "),
+            AUDIO_CODECS.join(", ")
+        );
+    }
+    match profile {
+        Some(_) if name != "H264" => bail!("codec {codec:?}: only H264 has profiles"),
+        Some(profile) if !H264_PROFILES.contains(&profile.as_str()) => bail!(
+            "codec {codec:?}: H264 profile {profile:?} should be one of {}",
+            H264_PROFILES.join(", ")
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// "h264/High" → ("H264", Some("high")).
+pub fn split_codec(codec: &str) -> (String, Option<String>) {
+    match codec.split_once('/') {
+        Some((name, profile)) => (name.to_uppercase(), Some(profile.to_lowercase())),
+        None => (codec.to_uppercase(), None),
+    }
+}
+
+/// Caps for the "codec-preferences" of a `kind` ("audio" or "video")
+/// transceiver, the `codecs` of that kind in order. None when there are none,
+/// leaving it to webrtcbin.
+///
+/// Offers get our payload types, the first video codec the one we send with.
+/// Answers leave them to the peer's offer, and match H264 profiles at any level.
+pub fn codec_preferences(kind: &str, codecs: &[String], offer: bool) -> Option<gst::Caps> {
+    let kind_codecs = match kind {
+        "video" => VIDEO_CODECS,
+        "audio" => AUDIO_CODECS,
+        _ => return None,
+    };
+    let mut next_payload_type = match kind {
+        "video" => VIDEO_PAYLOAD_TYPE,
+        _ => AUDIO_PAYLOAD_TYPE,
+    };
+
+    let mut caps = gst::Caps::new_empty();
+    let caps_mut = caps.get_mut().expect("new caps are writable");
+    for codec in codecs {
+        let (name, profile) = split_codec(codec);
+        if !kind_codecs.contains(&name.as_str()) {
+            continue;
+        }
+
+        let clock_rate = if kind == "audio" { OPUS_RATE } else { 90_000 };
+        let mut structure = gst::Structure::builder("application/x-rtp")
+            .field("media", kind)
+            .field("encoding-name", name.as_str())
+            .field("clock-rate", clock_rate)
+            .build();
+
+        if let Some(profile) = profile {
+            let prefixes = H264_PROFILE_IDS
+                .iter()
+                .find(|(name, _)| *name == profile)
+                .map_or(&[][..], |(_, prefixes)| *prefixes);
+            let profile_level_ids = prefixes.iter().flat_map(|prefix| {
+                H264_LEVELS
+                    .iter()
+                    .map(move |level| format!("{prefix}{level}"))
+            });
+            structure.set("profile-level-id", gst::List::new(profile_level_ids));
+        }
+        if offer {
+            structure.set("payload", next_payload_type as i32);
+            next_payload_type += 1;
+            if name == "OPUS" {
+                // always "opus/48000/2" in SDP, whatever we send
+                structure.set("encoding-params", "2");
+            }
+        }
+
+        caps_mut.append_structure(structure);
+    }
+
+    (!caps.is_empty()).then_some(caps)
+}
+
+/// The elements `add_receive_branch` put in the pipeline for a track, to take
+/// out again when webrtcbin removes the track's pad.
+#[derive(Debug, Clone)]
+pub struct ReceiveBranch {
+    pub mid: String,
+    // None once removed, so a pad decodebin adds late doesn't get a sink
+    elements: Arc<StdMutex<Option<Vec<gst::Element>>>>,
+}
+
+impl ReceiveBranch {
+    fn new(mid: &str) -> Self {
+        Self {
+            mid: mid.to_string(),
+            elements: Arc::new(StdMutex::new(Some(vec![]))),
+        }
+    }
+
+    /// Take `element` out along with the rest.
+    pub fn add(&self, element: gst::Element) {
+        let mut elements = self.elements.lock().expect("failed to lock receive branch");
+        if let Some(elements) = elements.as_mut() {
+            elements.push(element);
+        }
+    }
+
+    /// Stop the branch's elements and take them out of `pipeline`, which
+    /// unlinks them. Not from one of their own streaming threads.
+    pub fn remove(&self, pipeline: &gst::Pipeline) {
+        let elements = self
+            .elements
+            .lock()
+            .expect("failed to lock receive branch")
+            .take()
+            .unwrap_or_default();
+        for element in &elements {
+            let _ = element.set_state(gst::State::Null);
+            let _ = pipeline.remove(element);
+        }
+    }
+}
+
+// webrtcbin src pad → decodebin, then on to `sink` once decodebin knows what it decodes to
+pub fn add_receive_branch(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    track: &IncomingTrack,
+    sink: &IncomingSink,
+    sample_callback: Option<SampleCallback>,
+) -> Result<ReceiveBranch> {
+    let stats = (*sink == IncomingSink::Stats).then(|| Arc::new(TrackStats::new()));
+    if let Some(stats) = &stats {
+        let stats = stats.clone();
+        let track = track.clone();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+            if let Some(gst::PadProbeData::Buffer(buffer)) = &info.data {
+                stats.packets.fetch_add(1, Ordering::Relaxed);
+                stats
+                    .bytes
+                    .fetch_add(buffer.size() as u64, Ordering::Relaxed);
+                stats.log_now_and_then(&track);
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    let branch = ReceiveBranch::new(&track.mid);
+
+    let decodebin = gst::ElementFactory::make("decodebin").build()?;
+    pipeline.add(&decodebin)?;
+    branch.add(decodebin.clone());
+    decodebin.sync_state_with_parent()?;
+
+    let pipeline_weak = pipeline.downgrade();
+    let track_clone = track.clone();
+    let sink = sink.clone();
+    let branch_clone = branch.clone();
+    decodebin.connect_pad_added(move |decodebin, decoded_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        // held until the sink is in, so removing waits for it
+        let mut branch_elements = branch_clone
+            .elements
+            .lock()
+            .expect("failed to lock receive branch");
+        let Some(elements) = branch_elements.as_mut() else {
+            return;
+        };
+
+        let track = &track_clone;
+        let linked = add_incoming_sink(
+            &pipeline,
+            decoded_pad,
+            track,
+            &sink,
+            stats.clone(),
+            sample_callback.clone(),
+        )
+        .map(|sink_elements| elements.extend(sink_elements));
+        if let Err(err) = linked {
+            // ends the session through the bus watch
+            gst::element_error!(
+                decodebin,
+                gst::CoreError::Negotiation,
+                ("mid {} {}: {err:#}", track.mid, track.codec)
+            );
+        }
+    });
+
+    let decodebin_sink = decodebin
+        .static_pad("sink")
+        .expect("decodebin has a sink pad");
+    pad.link(&decodebin_sink)
+        .with_context(|| format!("failed to link {} to decodebin", pad.name()))?;
+    Ok(branch)
+}
+
+// decoded pad → convert → the sink for its kind, returning what it added
+fn add_incoming_sink(
+    pipeline: &gst::Pipeline,
+    decoded_pad: &gst::Pad,
+    track: &IncomingTrack,
+    sink: &IncomingSink,
+    stats: Option<Arc<TrackStats>>,
+    sample_callback: Option<SampleCallback>,
+) -> Result<Vec<gst::Element>> {
+    let session_id = track.session_id;
+
+    let mut elements = vec![gst::ElementFactory::make("queue").build()?];
+    match track.kind.as_str() {
+        "video" => elements.push(gst::ElementFactory::make("videoconvert").build()?),
+        "audio" => {
+            elements.push(gst::ElementFactory::make("audioconvert").build()?);
+            elements.push(gst::ElementFactory::make("audioresample").build()?);
+        }
+        other => bail!("no sink for {other}"),
+    }
+
+    match sink {
+        IncomingSink::Stats => {
+            let fakesink = gst::ElementFactory::make("fakesink")
+                .property("sync", false)
+                .build()?;
+            if let Some(stats) = stats {
+                let fakesink_pad = fakesink
+                    .static_pad("sink")
+                    .expect("fakesink has a sink pad");
+                fakesink_pad.add_probe(gst::PadProbeType::BUFFER, move |_pad, _info| {
+                    stats.frames.fetch_add(1, Ordering::Relaxed);
+                    gst::PadProbeReturn::Ok
+                });
+            }
+            elements.push(fakesink);
+        }
+        IncomingSink::File(dir) => {
+            let (encoder, extension) = match track.kind.as_str() {
+                "video" => ("y4menc", "y4m"),
+                _ => ("wavenc", "wav"),
+            };
+            let path = incoming_file_path(dir, track, extension)?;
+            println!(
+                "[WebRTC Session {session_id}] WRITING mid {} to {}",
+                track.mid,
+                path.display()
+            );
+            elements.push(gst::ElementFactory::make(encoder).build()?);
+            elements.push(
+                gst::ElementFactory::make("filesink")
+                    .property("location", path.to_string_lossy().as_ref())
+                    .build()?,
+            );
+        }
+        IncomingSink::App => {
+            let track = track.clone();
+            let appsink = gst_app::AppSink::builder()
+                .sync(false)
+                .callbacks(
+                    gst_app::AppSinkCallbacks::builder()
+                        .new_sample(move |appsink| {
+                            let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                            // without a callback the samples are just dropped
+                            if let Some(callback) = &sample_callback {
+                                (callback.0)(&track, &sample);
+                            }
+                            Ok(gst::FlowSuccess::Ok)
+                        })
+                        .build(),
+                )
+                .build();
+            elements.push(appsink.upcast());
+        }
+    }
+
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+    for element in &elements {
+        element.sync_state_with_parent()?;
+    }
+
+    let first_pad = elements[0]
+        .static_pad("sink")
+        .expect("queue has a sink pad");
+    decoded_pad
+        .link(&first_pad)
+        .with_context(|| format!("failed to link decoded {}", decoded_pad.name()))?;
+    Ok(elements)
+}
+
+// "<dir>/session-<id>-<mid>.<extension>", creating `dir`
+fn incoming_file_path(dir: &Path, track: &IncomingTrack, extension: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    Ok(dir.join(format!(
+        "session-{}-{}.{extension}",
+        track.session_id, track.mid
+    )))
+}
+
+// what a "stats" sink counts
+struct TrackStats {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    frames: AtomicU64,
+    last_logged: StdMutex<Instant>,
+}
+
+impl TrackStats {
+    fn new() -> Self {
+        Self {
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            frames: AtomicU64::new(0),
+            last_logged: StdMutex::new(Instant::now()),
+        }
+    }
+
+    fn log_now_and_then(&self, track: &IncomingTrack) {
+        let mut last_logged = self.last_logged.lock().expect("failed to lock stats");
+        if last_logged.elapsed() < STATS_INTERVAL {
+            return;
+        }
+        *last_logged = Instant::now();
+
+        println!(
+            "[WebRTC Session {}] RECEIVING mid {} {} {}: {} packets, {} kB, {} decoded",
+            track.session_id,
+            track.mid,
+            track.kind,
+            track.codec,
+            self.packets.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed) / 1000,
+            self.frames.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Split a webrtcbin src pad with a tee, for a receive branch and a recording.
+pub fn split_pad(pipeline: &gst::Pipeline, pad: &gst::Pad) -> Result<(gst::Pad, gst::Pad)> {
+    let tee = gst::ElementFactory::make("tee").build()?;
+    pipeline.add(&tee)?;
+    tee.sync_state_with_parent()?;
+    pad.link(&tee.static_pad("sink").expect("tee has a sink pad"))
+        .with_context(|| format!("failed to link {} to tee", pad.name()))?;
+
+    let mut tee_pad = || {
+        tee.request_pad_simple("src_%u")
+            .ok_or_else(|| anyhow!("tee gave no src pad"))
+    };
+    Ok((tee_pad()?, tee_pad()?))
+}
+
+/// A track being written to a Matroska file, see `add_recording`.
+#[derive(Debug)]
+pub struct Recording {
+    pub path: PathBuf,
+    session_id: u64,
+    mid: String,
+    // where EOS goes in
+    sink_pad: gst::Pad,
+    // EOS reached the filesink
+    done: Arc<(StdMutex<bool>, Condvar)>,
+}
+
+impl Recording {
+    /// Push EOS through so the muxer writes its cues and durations, and wait
+    /// up to `timeout` for it to reach the file. Blocks, so not from a
+    /// streaming thread.
+    pub fn finish(&self, timeout: Duration) {
+        let session_id = self.session_id;
+        let (done, finished) = &*self.done;
+
+        let done = done.lock().expect("failed to lock recording");
+        if !*done && !self.sink_pad.send_event(gst::event::Eos::new()) {
+            println!(
+                "[WebRTC Session {session_id}] recording of mid {} didn't take EOS",
+                self.mid
+            );
+            return;
+        }
+        let (done, _) = finished
+            .wait_timeout_while(done, timeout, |done| !*done)
+            .expect("failed to lock recording");
+
+        if *done {
+            println!(
+                "[WebRTC Session {session_id}] RECORDED mid {} to {}",
+                self.mid,
+                self.path.display()
+            );
+        } else {
+            println!(
+                "[WebRTC Session {session_id}] recording of mid {} not finished after {timeout:?}, {} may be truncated",
+                self.mid,
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Record `pad` (RTP from the peer) to "<dir>/session-<id>-<mid>.mkv" (.mka
+/// for audio), as it was sent where we can depayload and parse the codec,
+/// otherwise decoded and encoded again as VP8 or Opus.
+pub fn add_recording(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    track: &IncomingTrack,
+    dir: &Path,
+) -> Result<Recording> {
+    let session_id = track.session_id;
+
+    let extension = match track.kind.as_str() {
+        "video" => "mkv",
+        "audio" => "mka",
+        other => bail!("can't record {other}"),
+    };
+    let path = incoming_file_path(dir, track, extension)?;
+
+    let queue = gst::ElementFactory::make("queue").build()?;
+    let mux = gst::ElementFactory::make("matroskamux").build()?;
+    let filesink = gst::ElementFactory::make("filesink")
+        .property("location", path.to_string_lossy().as_ref())
+        .build()?;
+    pipeline.add_many([&queue, &mux, &filesink])?;
+    mux.link(&filesink)?;
+
+    let mux_pad = mux
+        .request_pad_simple(&format!("{}_%u", track.kind))
+        .ok_or_else(|| anyhow!("matroskamux gave no {} pad", track.kind))?;
+
+    let passthrough = RECORDING_PASSTHROUGH
+        .iter()
+        .find(|(codec, _)| *codec == track.codec)
+        .map(|(_, elements)| *elements)
+        .filter(|elements| {
+            elements
+                .iter()
+                .all(|element| gst::ElementFactory::find(element).is_some())
+        });
+
+    match passthrough {
+        Some(names) => {
+            println!(
+                "[WebRTC Session {session_id}] RECORDING mid {} {} as sent to {}",
+                track.mid,
+                track.codec,
+                path.display()
+            );
+            let mut elements = vec![queue.clone()];
+            for name in names {
+                let element = gst::ElementFactory::make(name).build()?;
+                pipeline.add(&element)?;
+                elements.push(element);
+            }
+            gst::Element::link_many(&elements)?;
+            let last = elements.last().expect("recording has elements");
+            last.static_pad("src")
+                .expect("parser has a src pad")
+                .link(&mux_pad)
+                .with_context(|| format!("failed to link {} to matroskamux", last.name()))?;
+            for element in &elements[1..] {
+                element.sync_state_with_parent()?;
+            }
+        }
+        None => {
+            println!(
+                "[WebRTC Session {session_id}] RECORDING mid {} {} transcoded to {}",
+                track.mid,
+                track.codec,
+                path.display()
+            );
+            add_recording_transcoder(pipeline, &queue, mux_pad, track)?;
+        }
+    }
+
+    let done = Arc::new((StdMutex::new(false), Condvar::new()));
+    let done_clone = done.clone();
+    filesink
+        .static_pad("sink")
+        .expect("filesink has a sink pad")
+        .add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_pad, info| {
+            if let Some(gst::PadProbeData::Event(event)) = &info.data {
+                if event.type_() == gst::EventType::Eos {
+                    let (done, finished) = &*done_clone;
+                    *done.lock().expect("failed to lock recording") = true;
+                    finished.notify_all();
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+    for element in [&filesink, &mux, &queue] {
+        element.sync_state_with_parent()?;
+    }
+
+    let sink_pad = queue.static_pad("sink").expect("queue has a sink pad");
+    pad.link(&sink_pad)
+        .with_context(|| format!("failed to link {} to recording", pad.name()))?;
+
+    Ok(Recording {
+        path,
+        session_id,
+        mid: track.mid.clone(),
+        sink_pad,
+        done,
+    })
+}
+
+// queue → decodebin, then convert → VP8/Opus encoder → `mux_pad` once it decodes
+fn add_recording_transcoder(
+    pipeline: &gst::Pipeline,
+    queue: &gst::Element,
+    mux_pad: gst::Pad,
+    track: &IncomingTrack,
+) -> Result<()> {
+    let decodebin = gst::ElementFactory::make("decodebin").build()?;
+    pipeline.add(&decodebin)?;
+    queue.link(&decodebin)?;
+
+    let pipeline_weak = pipeline.downgrade();
+    let kind = track.kind.clone();
+    decodebin.connect_pad_added(move |decodebin, decoded_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        let linked = (|| -> Result<()> {
+            let elements = match kind.as_str() {
+                "video" => vec![
+                    gst::ElementFactory::make("videoconvert").build()?,
+                    gst::ElementFactory::make("vp8enc")
+                        .property("deadline", 1i64)
+                        .build()?,
+                ],
+                _ => vec![
+                    gst::ElementFactory::make("audioconvert").build()?,
+                    gst::ElementFactory::make("audioresample").build()?,
+                    gst::ElementFactory::make("opusenc").build()?,
+                ],
+            };
+            pipeline.add_many(&elements)?;
+            gst::Element::link_many(&elements)?;
+            elements
+                .last()
+                .and_then(|encoder| encoder.static_pad("src"))
+                .expect("encoder has a src pad")
+                .link(&mux_pad)?;
+            for element in &elements {
+                element.sync_state_with_parent()?;
+            }
+            decoded_pad.link(
+                &elements[0]
+                    .static_pad("sink")
+                    .expect("converter has a sink pad"),
+            )?;
+            Ok(())
+        })();
+        if let Err(err) = linked {
+            // ends the session through the bus watch
+            gst::element_error!(
+                decodebin,
+                gst::CoreError::Negotiation,
+                ("failed to transcode for recording: {err:#}")
+            );
+        }
+    });
+
+    decodebin.sync_state_with_parent()?;
+    Ok(())
+}
@@ -2,12 +2,30 @@
 #![allow(unused_imports)]
 
 use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
 use std::ops::{Deref, Drop};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 
 use gst::prelude::*;
 use gst_rtp::prelude::*;
 
+use crate::candidate_filter::{
+    candidate_address, with_candidate_address, CandidateFilter, Decision,
+};
+use crate::error::*;
+use crate::events::*;
+use crate::ice_agent::IceAgentOptions;
+use crate::ice_servers::IceServers;
+use crate::mdns;
+use crate::media::{
+    self, AudioSendOptions, IncomingSink, IncomingTrack, ReceiveBranch, Recording, SampleCallback,
+    VideoSendOptions,
+};
 use crate::signals::*;
 
 // upgrade weak reference or return
@@ -27,16 +45,217 @@ macro_rules! upgrade_weak {
 #[derive(Debug, Clone)]
 pub struct Session(Arc<Inner>);
 
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// Send ICE candidates to the peer as they are gathered. When false the
+    /// offer/answer is held until gathering is complete and sent with all
+    /// candidates embedded, for peers that can't do trickle ICE.
+    pub trickle_ice: bool,
+
+    /// Create the offer ourselves when webrtcbin needs negotiation, rather
+    /// than waiting for one from the peer.
+    pub backend_offers: bool,
+
+    /// Perfect negotiation role. On an offer collision the polite peer rolls
+    /// back its own offer, the impolite peer ignores the remote one.
+    pub polite: bool,
+
+    /// How long ICE may stay disconnected before we restart it.
+    pub ice_disconnected_grace: Duration,
+
+    pub negotiation_timeouts: NegotiationTimeouts,
+
+    /// How many more times to try create-answer when it doesn't give us an answer.
+    pub create_answer_retries: u32,
+
+    /// webrtcbin jitterbuffer size, ms.
+    pub latency: u32,
+
+    /// webrtcbin "bundle-policy", e.g. "max-compat".
+    pub bundle_policy: String,
+
+    /// STUN/TURN servers, the same list is sent to the browser.
+    pub ice_servers: IceServers,
+
+    /// "all", or "relay" to only use TURN relayed candidates.
+    pub ice_transport_policy: String,
+
+    /// Protocols, ports and interfaces the ICE agent gathers on.
+    pub ice_agent: IceAgentOptions,
+
+    /// Applied to our candidates before they're sent, trickled or in the SDP.
+    pub local_candidate_filter: CandidateFilter,
+
+    /// Applied to the peer's trickled candidates before they're added.
+    pub remote_candidate_filter: CandidateFilter,
+
+    /// Resolve the peer's mDNS ("<uuid>.local") host candidates by asking
+    /// this address, None to hand them to webrtcbin as they are.
+    pub mdns_server: Option<SocketAddr>,
+
+    /// How long to wait for an mDNS answer.
+    pub mdns_timeout: Duration,
+
+    /// Test video to send, None to only receive.
+    pub video_send: Option<VideoSendOptions>,
+
+    /// Test audio to send, None to only receive.
+    pub audio_send: Option<AudioSendOptions>,
+
+    /// Codecs to negotiate in order of preference ("VP9", "H264/high", "OPUS"),
+    /// set on each transceiver before offers and answers. Empty for anything.
+    pub codec_preferences: Vec<String>,
+
+    /// Where decoded video from the peer goes.
+    pub video_receive: IncomingSink,
+
+    /// Where decoded audio from the peer goes.
+    pub audio_receive: IncomingSink,
+
+    /// Gets every sample of an `IncomingSink::App`.
+    pub sample_callback: Option<SampleCallback>,
+
+    /// Record each track from the peer to a Matroska file in this directory.
+    pub record_dir: Option<PathBuf>,
+}
+
+/// Where a session is in its life, driven by signals and webrtcbin state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    New,
+    Negotiating,
+    Connecting,
+    Connected,
+    Reconnecting,
+    Ending,
+    Ended,
+}
+
+impl SessionState {
+    pub fn can_become(self, next: SessionState) -> bool {
+        use SessionState::*;
+
+        match (self, next) {
+            // anything still alive can end
+            (Ending | Ended, Ending) => false,
+            (_, Ending) => true,
+            (Ending, Ended) => true,
+
+            (New, Negotiating) => true,
+            (Negotiating, Connecting | Connected) => true,
+            (Connecting, Connected) => true,
+            (Connected, Reconnecting) => true,
+            (Reconnecting, Connected) => true,
+
+            // renegotiation, including ICE restarts
+            (Connecting | Connected | Reconnecting, Negotiating) => true,
+
+            _ => false,
+        }
+    }
+}
+
+/// How long each negotiation phase may take before it's reported as failed.
+#[derive(Debug, Clone)]
+pub struct NegotiationTimeouts {
+    /// create-answer promise reply
+    pub create_answer: Duration,
+    /// ICE gathering, from starting until complete
+    pub ice_gathering: Duration,
+    /// from setting our offer/answer until connected
+    pub connection: Duration,
+}
+
+impl Default for NegotiationTimeouts {
+    fn default() -> Self {
+        Self {
+            create_answer: Duration::from_secs(5),
+            ice_gathering: Duration::from_secs(10),
+            connection: Duration::from_secs(30),
+        }
+    }
+}
+
+// how long recordings get to finish their files when a session ends by itself
+const RECORDING_EOS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// an m-line negotiated with the peer
+#[derive(Debug)]
+struct Media {
+    line_index: u32,
+    // "audio", "video" or "application"
+    kind: String,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            trickle_ice: true,
+            backend_offers: false,
+            // the browser is polite
+            polite: false,
+            ice_disconnected_grace: Duration::from_secs(5),
+            negotiation_timeouts: NegotiationTimeouts::default(),
+            create_answer_retries: 2,
+            latency: 30,
+            // allow as many media streams as the peer wants
+            bundle_policy: "max-compat".to_string(),
+            ice_servers: IceServers::default(),
+            ice_transport_policy: "all".to_string(),
+            ice_agent: IceAgentOptions::default(),
+            local_candidate_filter: CandidateFilter::default(),
+            remote_candidate_filter: CandidateFilter::default(),
+            mdns_server: Some(mdns::MDNS_ADDRESS),
+            mdns_timeout: Duration::from_secs(1),
+            video_send: None,
+            audio_send: None,
+            codec_preferences: vec![],
+            video_receive: IncomingSink::Stats,
+            audio_receive: IncomingSink::Stats,
+            sample_callback: None,
+            record_dir: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SessionWeak(Weak<Inner>);
 
 #[derive(Debug)]
 pub struct Inner {
     session_id: u64,
+    options: SessionOptions,
     outgoing_signal_tx: Arc<SignalSender>,
+    session_ended_tx: mpsc::UnboundedSender<u64>,
+    session_event_tx: SessionEventSender,
+    // for negotiation started from webrtcbin/GLib callbacks
+    runtime: tokio::runtime::Handle,
     pipeline: gst::Pipeline,
     bus_watch: Arc<StdMutex<Option<gst::bus::BusWatchGuard>>>,
     webrtcbin: gst::Element,
+    // non-trickle: offer/answer waiting for ICE gathering to complete
+    local_description_held: StdMutex<bool>,
+    // negotiated m-lines by mid, kept across renegotiations
+    media: StdMutex<HashMap<String, Media>>,
+    // from create-offer until webrtcbin reaches have-local-offer
+    making_offer: StdMutex<bool>,
+    // descriptions are set one exchange at a time, so an offer can't land
+    // halfway through answering the peer's (the browser's operations chain)
+    negotiation: tokio::sync::Mutex<()>,
+    // remote offers taken so far, an offer created before the latest is stale
+    remote_offers: StdMutex<u64>,
+    // ICE restarted since last connected, so a failure now ends the session
+    ice_restarted: StdMutex<bool>,
+    // reached Connected at least once, from then on losing the connection is
+    // ICE's to handle, not the connection watchdog's
+    has_connected: StdMutex<bool>,
+    state: StdMutex<SessionState>,
+    // EOS reached the bus
+    eos: Arc<tokio::sync::Notify>,
+    // tracks being recorded, finished when the session ends
+    recordings: StdMutex<Vec<Recording>>,
+    // what each webrtcbin src pad feeds, by pad name
+    receive_branches: StdMutex<HashMap<String, ReceiveBranch>>,
 }
 
 impl Deref for Session {
@@ -59,6 +278,20 @@ impl Inner {
     fn end(&self) {
         let session_id = self.session_id;
         println!("[WebRTC Session {session_id}] END");
+
+        // may be called from a webrtcbin thread, so don't change state or
+        // wait for recordings from here
+        let recordings = self.take_recordings();
+        self.pipeline.call_async(move |pipeline| {
+            for recording in &recordings {
+                recording.finish(RECORDING_EOS_TIMEOUT);
+            }
+            let _ = pipeline.set_state(gst::State::Null);
+        });
+    }
+
+    fn take_recordings(&self) -> Vec<Recording> {
+        std::mem::take(&mut *self.recordings.lock().expect("failed to lock recordings"))
     }
 }
 
@@ -79,22 +312,27 @@ impl Session {
         SessionWeak(Arc::downgrade(&self.0))
     }
 
-    pub fn start(session_id: u64, outgoing_signal_tx: Arc<SignalSender>) -> Result<Self> {
+    pub fn start(
+        session_id: u64,
+        options: SessionOptions,
+        outgoing_signal_tx: Arc<SignalSender>,
+        session_ended_tx: mpsc::UnboundedSender<u64>,
+        session_event_tx: SessionEventSender,
+    ) -> SessionResult<Self> {
         println!("[WebRTC Session {session_id}] START");
 
         let webrtcbin = gst::ElementFactory::make("webrtcbin")
-            .property("latency", 30u32) // jitterbuffer size, ms (default 200)
+            .property("latency", options.latency) // jitterbuffer size, ms (default 200)
             .build()
-            .expect("failed to make webrtcbin");
+            .map_err(|err| SessionError::Pipeline(err.into()))?;
 
         // ICE servers
-        // webrtcbin.set_property_from_str("stun-server", &ice_servers.stun_server_uri);
-        // if let Some(uri) = ice_servers.turn_server_uri_with_auth() {
-        //     webrtcbin.set_property_from_str("turn-server", &uri);
-        // }
+        configure_ice_servers(session_id, &webrtcbin, &options.ice_servers)?;
+        // webrtcbin passes this on to the ICE agent as "force relay"
+        webrtcbin.set_property_from_str("ice-transport-policy", &options.ice_transport_policy);
+        configure_ice_agent(session_id, &webrtcbin, &options.ice_agent)?;
 
-        // allow as many media streams as the peer wants
-        webrtcbin.set_property_from_str("bundle-policy", "max-compat");
+        webrtcbin.set_property_from_str("bundle-policy", &options.bundle_policy);
 
         // create empty pipeline
         let pipeline = gst::Pipeline::builder()
@@ -102,23 +340,35 @@ impl Session {
             .build();
 
         // add the webrtcbin element
-        pipeline.add(&webrtcbin).unwrap();
-
-        // start playing
         pipeline
-            .set_state(gst::State::Playing)
-            .expect("failed to set the pipeline to the `Playing` state");
+            .add(&webrtcbin)
+            .map_err(|err| SessionError::Pipeline(err.into()))?;
 
         let session = Session(Arc::new(Inner {
             session_id,
+            options,
             outgoing_signal_tx,
+            session_ended_tx,
+            session_event_tx,
+            runtime: tokio::runtime::Handle::current(),
             pipeline,
             bus_watch: Arc::new(StdMutex::new(None)),
             webrtcbin,
+            local_description_held: StdMutex::new(false),
+            media: StdMutex::new(HashMap::new()),
+            making_offer: StdMutex::new(false),
+            negotiation: tokio::sync::Mutex::new(()),
+            remote_offers: StdMutex::new(0),
+            ice_restarted: StdMutex::new(false),
+            has_connected: StdMutex::new(false),
+            state: StdMutex::new(SessionState::New),
+            eos: Arc::new(tokio::sync::Notify::new()),
+            recordings: StdMutex::new(vec![]),
+            receive_branches: StdMutex::new(HashMap::new()),
         }));
 
         // consume bus messages
-        session.watch_bus()?;
+        session.watch_bus().map_err(SessionError::Pipeline)?;
 
         //////////////////////////////////////////////////////////////////////
         // hook up GStreamer signals
@@ -135,13 +385,49 @@ impl Session {
                 let session = upgrade_weak!(session_clone, None);
 
                 if let Err(err) = session.on_local_ice_candidate(candidate, line_index) {
-                    gst::element_error!(
-                        session.pipeline,
-                        gst::LibraryError::Failed,
-                        ("failed to send ICE candidate: {:?}", err)
-                    );
+                    session.fail(SessionError::Ice(err));
+                }
+
+                None
+            });
+
+        // media from the peer
+        let session_clone = session.downgrade();
+        session
+            .webrtcbin
+            .connect("pad-added", false, move |values| {
+                let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
+                let pad = values[1].get::<gst::Pad>().expect("Invalid argument");
+
+                // our own sink pads show up here too
+                if pad.direction() != gst::PadDirection::Src {
+                    return None;
+                }
+
+                let session = upgrade_weak!(session_clone, None);
+
+                if let Err(err) = session.on_incoming_pad(&pad) {
+                    session.fail(SessionError::Pipeline(err));
+                }
+
+                None
+            });
+
+        // a track stopped or replaced by renegotiation
+        let session_clone = session.downgrade();
+        session
+            .webrtcbin
+            .connect("pad-removed", false, move |values| {
+                let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
+                let pad = values[1].get::<gst::Pad>().expect("Invalid argument");
+
+                if pad.direction() != gst::PadDirection::Src {
+                    return None;
                 }
 
+                let session = upgrade_weak!(session_clone, None);
+                session.on_incoming_pad_removed(&pad);
+
                 None
             });
 
@@ -158,11 +444,7 @@ impl Session {
                 let session = upgrade_weak!(session_clone, None);
 
                 if let Err(err) = session.on_data_channel(data_channel) {
-                    gst::element_error!(
-                        session.pipeline,
-                        gst::LibraryError::Failed,
-                        ("failed to handle data channel: {:?}", err)
-                    );
+                    session.fail(SessionError::DataChannel(err));
                 }
 
                 None
@@ -176,11 +458,7 @@ impl Session {
                 let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
                 let session = upgrade_weak!(session_clone, None);
                 if let Err(err) = session.on_connection_state_changed() {
-                    gst::element_error!(
-                        session.pipeline,
-                        gst::LibraryError::Failed,
-                        ("failed to handle connection state change: {:?}", err)
-                    );
+                    session.fail(SessionError::Ice(err));
                 }
 
                 None
@@ -194,11 +472,21 @@ impl Session {
                 let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
                 let session = upgrade_weak!(session_clone, None);
                 if let Err(err) = session.on_ice_connection_state_changed() {
-                    gst::element_error!(
-                        session.pipeline,
-                        gst::LibraryError::Failed,
-                        ("failed to handle ICE connection state change: {:?}", err)
-                    );
+                    session.fail(SessionError::Ice(err));
+                }
+
+                None
+            });
+
+        // ICE gathering state
+        let session_clone = session.downgrade();
+        session
+            .webrtcbin
+            .connect("notify::ice-gathering-state", false, move |values| {
+                let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
+                let session = upgrade_weak!(session_clone, None);
+                if let Err(err) = session.on_ice_gathering_state_changed() {
+                    session.fail(SessionError::Ice(err));
                 }
 
                 None
@@ -212,11 +500,21 @@ impl Session {
                 let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
                 let session = upgrade_weak!(session_clone, None);
                 if let Err(err) = session.on_signaling_state_changed() {
-                    gst::element_error!(
-                        session.pipeline,
-                        gst::LibraryError::Failed,
-                        ("failed to handle signaling state change: {:?}", err)
-                    );
+                    session.fail(SessionError::Negotiation(err));
+                }
+
+                None
+            });
+
+        // negotiation needed
+        let session_clone = session.downgrade();
+        session
+            .webrtcbin
+            .connect("on-negotiation-needed", false, move |values| {
+                let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
+                let session = upgrade_weak!(session_clone, None);
+                if let Err(err) = session.on_negotiation_needed() {
+                    session.fail(SessionError::Negotiation(err));
                 }
 
                 None
@@ -224,6 +522,49 @@ impl Session {
 
         //////////////////////////////////////////////////////////////////////
 
+        // media we send, each with its own transceiver; only now that the
+        // handlers are connected, so no pad or negotiation is missed
+        if let Some(video_send) = &session.options.video_send {
+            media::add_video_send(
+                session_id,
+                &session.pipeline,
+                &session.webrtcbin,
+                video_send,
+            )
+            .map_err(SessionError::Pipeline)?;
+        }
+        if let Some(audio_send) = &session.options.audio_send {
+            media::add_audio_send(
+                session_id,
+                &session.pipeline,
+                &session.webrtcbin,
+                audio_send,
+            )
+            .map_err(SessionError::Pipeline)?;
+        }
+
+        // start playing
+        session
+            .pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|err| SessionError::Pipeline(err.into()))?;
+
+        if session.options.backend_offers {
+            // give the offer something to negotiate
+            let data_channel = session
+                .webrtcbin
+                .emit_by_name::<Option<gst_webrtc::WebRTCDataChannel>>(
+                    "create-data-channel",
+                    &[&"backend data channel", &None::<gst::Structure>],
+                )
+                .ok_or_else(|| {
+                    SessionError::DataChannel(anyhow!("failed to create data channel"))
+                })?;
+            session
+                .on_data_channel(data_channel)
+                .map_err(SessionError::DataChannel)?;
+        }
+
         // session.write_debug_dot_file("START");
 
         Ok(session)
@@ -232,19 +573,157 @@ impl Session {
     /// Stop the session.
     pub fn stop(&self) -> Result<()> {
         let session_id = self.session_id();
+
+        // already stopping or stopped
+        if self.transition(SessionState::Ending).is_err() {
+            return Ok(());
+        }
+
         println!("[WebRTC Session {session_id}] STOP");
 
         // clean up
         self.0.end();
+        self.transition(SessionState::Ended)?;
+
+        // have WebRtc forget about us
+        self.session_ended_tx.send(session_id)?;
+
+        self.send_event(SessionEvent::Ended { session_id });
+
+        Ok(())
+    }
+
+    /// Say bye to the peer and wind the pipeline down through EOS.
+    pub async fn shutdown(&self, eos_timeout: Duration) -> Result<()> {
+        let session_id = self.session_id();
+        println!("[WebRTC Session {session_id}] SHUTDOWN");
+
+        self.outgoing_signal_tx.send(Signal::Bye {
+            data: ByeData { session_id },
+        })?;
+
+        // watch_bus notifies on EOS
+        if self.pipeline.send_event(gst::event::Eos::new()) {
+            if tokio::time::timeout(eos_timeout, self.eos.notified())
+                .await
+                .is_err()
+            {
+                println!("[WebRTC Session {session_id}] no EOS after {eos_timeout:?}");
+            }
+        } else {
+            println!("[WebRTC Session {session_id}] pipeline didn't take EOS");
+        }
+
+        // the pipeline's EOS may not get to the tracks we receive
+        let recordings = self.take_recordings();
+        tokio::task::spawn_blocking(move || {
+            for recording in &recordings {
+                recording.finish(eos_timeout);
+            }
+        })
+        .await?;
+
+        self.pipeline.set_state(gst::State::Null)?;
+
+        self.stop()
+    }
+
+    pub fn state(&self) -> SessionState {
+        *self.state.lock().expect("failed to lock session state")
+    }
+
+    // move to the next state, if that's legal from where we are
+    fn transition(&self, next: SessionState) -> Result<()> {
+        let session_id = self.session_id();
+        let mut state = self.state.lock().expect("failed to lock session state");
+
+        let current = *state;
+        if current == next {
+            return Ok(());
+        }
+        if !current.can_become(next) {
+            bail!("session {session_id} can't go from {current:?} to {next:?}");
+        }
+
+        println!("[WebRTC Session {session_id}] STATE {current:?} → {next:?}");
+        *state = next;
+        drop(state);
+
+        if next == SessionState::Connected {
+            *self
+                .has_connected
+                .lock()
+                .expect("failed to lock has connected") = true;
+        }
+
+        self.send_event(SessionEvent::StateChanged {
+            session_id,
+            state: next,
+        });
 
         Ok(())
     }
 
+    // for webrtcbin notifications, which happen regardless of our state
+    fn try_transition(&self, next: SessionState) {
+        if let Err(err) = self.transition(next) {
+            let session_id = self.session_id();
+            println!("[WebRTC Session {session_id}] ignoring state change: {err}");
+        }
+    }
+
+    // the application may have stopped listening, which is fine
+    fn send_event(&self, event: SessionEvent) {
+        if self.session_event_tx.send(event).is_err() {
+            let session_id = self.session_id();
+            println!("[WebRTC Session {session_id}] no session event listener");
+        }
+    }
+
+    /// Report an error to the application and the peer, and end the session
+    /// if it's fatal.
+    pub fn fail(&self, err: SessionError) {
+        let session_id = self.session_id();
+        let code = err.code();
+        let fatal = err.is_fatal();
+        let error_message = err.to_string();
+        println!("[WebRTC Session {session_id}] ERROR ({code}, fatal {fatal}) {error_message}");
+
+        self.send_event(SessionEvent::Error {
+            session_id,
+            code,
+            error_message: error_message.clone(),
+            fatal,
+        });
+
+        let signal = Signal::Error {
+            data: ErrorData {
+                session_id,
+                code: code.to_string(),
+                message: error_message,
+                fatal,
+            },
+        };
+        if self.outgoing_signal_tx.send(signal).is_err() {
+            println!("[WebRTC Session {session_id}] no signaling, peer not told");
+        }
+
+        if fatal {
+            if let Err(err) = self.stop() {
+                println!("[WebRTC Session {session_id}] failed to stop: {err:?}");
+            }
+        }
+    }
+
     fn watch_bus(&self) -> Result<()> {
         let session_id = self.session_id();
 
-        let bus = self.pipeline.bus().unwrap();
+        let bus = self
+            .pipeline
+            .bus()
+            .ok_or_else(|| anyhow!("pipeline has no bus"))?;
         let session_clone = self.downgrade();
+        let eos = self.eos.clone();
         let bus_watch = bus.add_watch(move |_bus, message| {
             // println!("[WebRTC Session {session_id}] BUS MESSAGE {:?}", message);
 
@@ -260,6 +739,7 @@ impl Session {
                 }
                 MessageView::Eos(..) => {
                     println!("[WebRTC Session {session_id}] end of bus stream");
+                    eos.notify_one();
                     glib::ControlFlow::Break
                 }
                 _ => glib::ControlFlow::Continue,
@@ -288,151 +768,1032 @@ impl Session {
                 err.error()
             );
 
-            // stop session on any error
-            // let _ = self.stop();
+            self.fail(SessionError::Pipeline(anyhow!("{src}: {}", err.error())));
         }
     }
 
-    fn on_local_answer(
+    // set our offer/answer locally and send it now, or once ICE gathering completes
+    async fn apply_local_description(
         &self,
-        reply: Result<Option<&gst::StructureRef>, gst::PromiseError>,
-    ) -> Result<(), anyhow::Error> {
+        description: gst_webrtc::WebRTCSessionDescription,
+    ) -> Result<()> {
         let session_id = self.session_id();
 
-        let reply = match reply {
-            Ok(Some(reply)) => reply,
-            Ok(None) => {
-                bail!("local answer is empty");
-            }
-            Err(err) => {
-                bail!("local answer error: {:?}", err);
-            }
-        };
+        if !self.options.trickle_ice {
+            // sent from on_ice_gathering_state_changed once all candidates are in,
+            // flagged before gathering can start
+            println!("[WebRTC Session {session_id}] holding local description for ICE gathering");
+            *self
+                .local_description_held
+                .lock()
+                .expect("failed to lock local description held") = true;
+        }
 
-        match reply.value("answer") {
-            Ok(answer) => {
-                let answer = answer
-                    .get::<gst_webrtc::WebRTCSessionDescription>()
-                    .expect("Invalid argument");
+        // emitted now but awaited after sending, so the peer gets our
+        // description before any of our candidates
+        let applied = self.promise_action("set-local-description", &[&description]);
 
-                self.webrtcbin
-                    .emit_by_name::<()>("set-local-description", &[&answer, &None::<gst::Promise>]);
+        if self.options.trickle_ice {
+            self.send_local_description(&description)?;
+        }
 
-                let sdp = answer.sdp().as_text().expect("answer has no SDP");
+        applied.await?;
 
-                // send answer
-                self.outgoing_signal_tx.send(Signal::WebRtcAnswer {
-                    data: WebRtcAnswerData {
-                        session_id,
-                        webrtc_data: WebRtcData {
-                            data_type: Some("answer".to_string()),
-                            sdp,
-                        },
-                    },
-                })?;
-            }
-            Err(e) => {
-                // sometimes there is no answer in the answer :\
-                println!("[WebRTC Session {session_id}] answer error: {e:?} reply: {reply:?}");
-                bail!("no answer in local answer: {:?}", e);
-            }
+        // a renegotiation with no new transports gathers nothing, so there's
+        // no Complete notification coming for the held description
+        if !self.options.trickle_ice
+            && self
+                .webrtcbin
+                .property::<gst_webrtc::WebRTCICEGatheringState>("ice-gathering-state")
+                == gst_webrtc::WebRTCICEGatheringState::Complete
+        {
+            self.send_held_local_description()?;
         }
 
-        Ok(())
-    }
+        if description.type_() == gst_webrtc::WebRTCSDPType::Answer {
+            self.log_negotiated_codecs(description.sdp());
+        }
+
+        self.watch_connection();
 
-    fn on_local_ice_candidate(&self, candidate: String, line_index: u32) -> Result<()> {
-        let session_id = self.session_id();
-        self.outgoing_signal_tx.send(Signal::IceCandidate {
-            data: IceCandidateData {
-                session_id,
-                webrtc_data: IceCandidateWebRtcData {
-                    candidate,
-                    media_id: None,
-                    line_index: Some(line_index),
-                    username_fragment: None,
-                },
-            },
-        })?;
         Ok(())
     }
 
-    pub fn on_remote_offer(&mut self, offer: WebRtcData) -> Result<()> {
-        let ret = gst_sdp::SDPMessage::parse_buffer(offer.sdp.as_bytes())
-            .map_err(|_| anyhow!("failed to parse SDP offer"))?;
+    // give up on the session if it doesn't connect in time
+    // only for the first negotiation, renegotiations and ICE restarts of a
+    // connected session have Reconnecting and the restart grace instead
+    fn watch_connection(&self) {
+        if self.has_connected() {
+            return;
+        }
 
         let session_clone = self.downgrade();
-        self.pipeline.call_async(move |_pipeline| {
+        let timeout = self.options.negotiation_timeouts.connection;
+        glib::timeout_add_once(timeout, move || {
             let session = upgrade_weak!(session_clone);
+            if session.has_connected() {
+                return;
+            }
 
-            let offer =
-                gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Offer, ret);
-
-            session
-                .0
-                .webrtcbin
-                .emit_by_name::<()>("set-remote-description", &[&offer, &None::<gst::Promise>]);
-
-            let session_clone = session.downgrade();
-            let promise = gst::Promise::with_change_func(move |reply| {
-                let session = upgrade_weak!(session_clone);
-
-                if let Err(err) = session.on_local_answer(reply) {
-                    gst::element_error!(
-                        session.pipeline,
-                        gst::LibraryError::Failed,
-                        ("failed to send answer: {:?}", err)
-                    );
-                }
-            });
-
-            session
-                .0
+            let connection_state = session
                 .webrtcbin
-                .emit_by_name::<()>("create-answer", &[&None::<gst::Structure>, &promise]);
+                .property::<gst_webrtc::WebRTCPeerConnectionState>("connection-state");
+            if connection_state != gst_webrtc::WebRTCPeerConnectionState::Connected {
+                let detail = format!("not connected after {timeout:?}");
+                session.report_negotiation_failure("connection", &detail);
+                session.fail(SessionError::Negotiation(anyhow!(detail)));
+            }
         });
-
-        Ok(())
     }
 
-    pub fn on_remote_answer(&mut self, answer: WebRtcData) -> Result<()> {
-        let ret = gst_sdp::SDPMessage::parse_buffer(answer.sdp.as_bytes())
-            .map_err(|_| anyhow!("failed to parse SDP answer"))?;
-        let answer =
-            gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, ret);
+    fn has_connected(&self) -> bool {
+        *self
+            .has_connected
+            .lock()
+            .expect("failed to lock has connected")
+    }
 
-        self.webrtcbin
-            .emit_by_name::<()>("set-remote-description", &[&answer, &None::<gst::Promise>]);
+    // report ICE gathering that never completes, and don't keep the peer
+    // waiting for a held description
+    fn watch_ice_gathering(&self) {
+        let session_clone = self.downgrade();
+        let timeout = self.options.negotiation_timeouts.ice_gathering;
+        glib::timeout_add_once(timeout, move || {
+            let session = upgrade_weak!(session_clone);
 
-        Ok(())
+            let ice_gathering_state = session
+                .webrtcbin
+                .property::<gst_webrtc::WebRTCICEGatheringState>("ice-gathering-state");
+            if ice_gathering_state == gst_webrtc::WebRTCICEGatheringState::Gathering {
+                session.report_negotiation_failure(
+                    "ice-gathering",
+                    &format!("not complete after {timeout:?}"),
+                );
+                if let Err(err) = session.send_held_local_description() {
+                    session.fail(SessionError::Ice(err));
+                }
+            }
+        });
     }
 
-    pub fn on_remote_ice_candidate(&mut self, ice_candidate_data: IceCandidateWebRtcData) {
-        let IceCandidateWebRtcData {
-            candidate,
-            line_index,
-            ..
-        } = ice_candidate_data;
+    // with webrtcbin's state at the time, for upstream bug reports
+    fn report_negotiation_failure(&self, phase: &str, detail: &str) {
+        let session_id = self.session_id();
+        let webrtcbin_state = self.webrtcbin_state();
+        println!("[WebRTC Session {session_id}] NEGOTIATION FAILURE in {phase}: {detail}");
+        println!("  {webrtcbin_state}");
 
-        if let Some(line_index) = line_index {
-            self.webrtcbin
-                .emit_by_name::<()>("add-ice-candidate", &[&line_index, &candidate]);
-        }
+        self.write_debug_dot_file(&format!("{phase}-failure"));
     }
 
-    //////////////////////////////////////////////////////////////////////////
-
-    fn on_connection_state_changed(&self) -> Result<()> {
+    fn webrtcbin_state(&self) -> String {
+        let signaling_state = self
+            .webrtcbin
+            .property::<gst_webrtc::WebRTCSignalingState>("signaling-state");
+        let ice_gathering_state = self
+            .webrtcbin
+            .property::<gst_webrtc::WebRTCICEGatheringState>("ice-gathering-state");
+        let ice_connection_state = self
+            .webrtcbin
+            .property::<gst_webrtc::WebRTCICEConnectionState>("ice-connection-state");
         let connection_state = self
             .webrtcbin
             .property::<gst_webrtc::WebRTCPeerConnectionState>("connection-state");
 
-        let session_id = self.session_id();
-        println!("[WebRTC Session {session_id}] CONNECTION STATE → {connection_state:?}");
+        let description = |name: &str| match self
+            .webrtcbin
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>(name)
+        {
+            Some(description) => format!("{:?}", description.type_()),
+            None => "none".to_string(),
+        };
+
+        format!(
+            "webrtcbin signaling {signaling_state:?}, ICE gathering {ice_gathering_state:?}, \
+             ICE connection {ice_connection_state:?}, connection {connection_state:?}, \
+             local description {}, remote description {}",
+            description("local-description"),
+            description("remote-description"),
+        )
+    }
+
+    /// Write the pipeline graph to $GST_DEBUG_DUMP_DOT_DIR, if set.
+    pub fn write_debug_dot_file(&self, label: &str) {
+        let session_id = self.session_id();
+        gst::debug_bin_to_dot_file_with_ts(
+            &self.pipeline,
+            gst::DebugGraphDetails::all(),
+            format!("session-{session_id}-{label}"),
+        );
+    }
+
+    fn send_local_description(
+        &self,
+        description: &gst_webrtc::WebRTCSessionDescription,
+    ) -> Result<()> {
+        let session_id = self.session_id();
+        let sdp = self.filter_local_sdp(&description.sdp().as_text()?);
+
+        let signal = match description.type_() {
+            gst_webrtc::WebRTCSDPType::Offer => Signal::WebRtcOffer {
+                data: WebRtcOfferData {
+                    session_id,
+                    webrtc_data: WebRtcData {
+                        data_type: Some("offer".to_string()),
+                        sdp,
+                    },
+                },
+            },
+            gst_webrtc::WebRTCSDPType::Answer => Signal::WebRtcAnswer {
+                data: WebRtcAnswerData {
+                    session_id,
+                    webrtc_data: WebRtcData {
+                        data_type: Some("answer".to_string()),
+                        sdp,
+                    },
+                },
+            },
+            other => bail!("unexpected local description type {other:?}"),
+        };
+
+        self.outgoing_signal_tx.send(signal)?;
+        Ok(())
+    }
+
+    // non-trickle: the local description now has every candidate embedded
+    fn send_held_local_description(&self) -> Result<()> {
+        let mut held = self
+            .local_description_held
+            .lock()
+            .expect("failed to lock local description held");
+        if !*held {
+            return Ok(());
+        }
+        *held = false;
+
+        let description = self
+            .webrtcbin
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>("local-description")
+            .ok_or_else(|| anyhow!("no local description after ICE gathering"))?;
+
+        let session_id = self.session_id();
+        println!(
+            "[WebRTC Session {session_id}] ICE gathering complete, sending held local description"
+        );
+        self.send_local_description(&description)
+    }
+
+    fn on_local_ice_candidate(&self, candidate: String, line_index: u32) -> Result<()> {
+        if !self.options.trickle_ice {
+            // embedded in the held local description instead
+            return Ok(());
+        }
+
+        let session_id = self.session_id();
+
+        let Some(candidate) =
+            self.filter_candidate("local", &self.options.local_candidate_filter, &candidate)
+        else {
+            return Ok(());
+        };
+
+        let media_id = self.media_id(line_index);
+        let username_fragment =
+            candidate_username_fragment(&candidate).or_else(|| self.local_ice_ufrag(line_index));
+
+        self.outgoing_signal_tx.send(Signal::IceCandidate {
+            data: IceCandidateData {
+                session_id,
+                webrtc_data: IceCandidateWebRtcData {
+                    candidate,
+                    media_id,
+                    line_index: Some(line_index),
+                    username_fragment,
+                },
+            },
+        })?;
+        Ok(())
+    }
+
+    // the candidate to use after the filter, None if dropped
+    fn filter_candidate(
+        &self,
+        side: &str,
+        filter: &CandidateFilter,
+        candidate: &str,
+    ) -> Option<String> {
+        if filter.is_empty() || candidate.is_empty() {
+            return Some(candidate.to_string());
+        }
+
+        let session_id = self.session_id();
+        let decision = filter.apply(candidate);
+        println!("[WebRTC Session {session_id}] {side} candidate {decision}: {candidate}");
+
+        match decision {
+            Decision::Keep => Some(candidate.to_string()),
+            Decision::Drop { .. } => None,
+            Decision::Rewrite { candidate, .. } => Some(candidate),
+        }
+    }
+
+    // candidates embedded in our offer/answer go through the same filter as trickled ones
+    fn filter_local_sdp(&self, sdp: &str) -> String {
+        let filter = &self.options.local_candidate_filter;
+        if filter.is_empty() {
+            return sdp.to_string();
+        }
+
+        sdp.split_inclusive("\r\n")
+            .filter_map(|line| {
+                let Some(candidate) = line.trim_end().strip_prefix("a=") else {
+                    return Some(line.to_string());
+                };
+                if !candidate.starts_with("candidate:") {
+                    return Some(line.to_string());
+                }
+                self.filter_candidate("local", filter, candidate)
+                    .map(|candidate| format!("a={candidate}\r\n"))
+            })
+            .collect()
+    }
+
+    // empty candidate with no mid or m-line index = no more candidates for any m-line
+    fn on_local_ice_gathering_complete(&self) -> Result<()> {
+        let session_id = self.session_id();
+        println!("[WebRTC Session {session_id}] END OF CANDIDATES");
+
+        self.outgoing_signal_tx.send(Signal::IceCandidate {
+            data: IceCandidateData {
+                session_id,
+                webrtc_data: IceCandidateWebRtcData {
+                    candidate: String::new(),
+                    media_id: None,
+                    line_index: None,
+                    username_fragment: None,
+                },
+            },
+        })?;
+        Ok(())
+    }
+
+    // mid of an m-line, from the local SDP or failing that the transceiver on that m-line
+    fn media_id(&self, line_index: u32) -> Option<String> {
+        self.local_media_attribute(line_index, "mid").or_else(|| {
+            self.transceivers()
+                .into_iter()
+                .find(|transceiver| transceiver.property::<u32>("mlineindex") == line_index)
+                .and_then(|transceiver| transceiver.property::<Option<String>>("mid"))
+        })
+    }
+
+    // every transceiver, in creation order; "get-transceiver" indexes these, not m-lines
+    fn transceivers(&self) -> Vec<gst_webrtc::WebRTCRTPTransceiver> {
+        (0i32..)
+            .map_while(|index| {
+                self.webrtcbin
+                    .emit_by_name::<Option<gst_webrtc::WebRTCRTPTransceiver>>(
+                        "get-transceiver",
+                        &[&index],
+                    )
+            })
+            .collect()
+    }
+
+    // ICE username fragment of an m-line, falling back to the session level one
+    fn local_ice_ufrag(&self, line_index: u32) -> Option<String> {
+        self.local_media_attribute(line_index, "ice-ufrag")
+            .or_else(|| {
+                let description = self
+                    .webrtcbin
+                    .property::<Option<gst_webrtc::WebRTCSessionDescription>>(
+                        "local-description",
+                    )?;
+                description
+                    .sdp()
+                    .attribute_val("ice-ufrag")
+                    .map(String::from)
+            })
+    }
+
+    // m-line of a mid in the peer's description, the reverse of media_id
+    fn remote_line_index(&self, mid: &str) -> Option<u32> {
+        let description = self
+            .webrtcbin
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>("remote-description")?;
+        let line_index = description
+            .sdp()
+            .medias()
+            .position(|media| media.attribute_val("mid") == Some(mid))?;
+        Some(line_index as u32)
+    }
+
+    fn remote_media_count(&self) -> u32 {
+        self.webrtcbin
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>("remote-description")
+            .map_or(0, |description| description.sdp().medias_len())
+    }
+
+    fn local_media_attribute(&self, line_index: u32, key: &str) -> Option<String> {
+        let description = self
+            .webrtcbin
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>("local-description")?;
+        let media = description.sdp().media(line_index)?;
+        media.attribute_val(key).map(String::from)
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+    // webrtcbin promise-based actions
+
+    // emit a webrtcbin action whose last argument is a promise; the returned
+    // future resolves with the promise's reply
+    fn promise_action(
+        &self,
+        action: &'static str,
+        args: &[&dyn ToValue],
+    ) -> impl Future<Output = Result<Option<gst::Structure>>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let promise = gst::Promise::with_change_func(move |reply| {
+            let _ = reply_tx.send(reply.map(|reply| reply.map(|reply| reply.to_owned())));
+        });
+
+        let mut args = args.to_vec();
+        args.push(&promise);
+        self.webrtcbin.emit_by_name::<()>(action, &args);
+
+        async move {
+            let reply = reply_rx
+                .await
+                .map_err(|_| anyhow!("{action} promise dropped"))?
+                .map_err(|err| anyhow!("{action} promise {err:?}"))?;
+
+            // webrtcbin replies with an error field on failure
+            if let Some(error) = reply
+                .as_ref()
+                .and_then(|reply| reply.get::<glib::Error>("error").ok())
+            {
+                bail!("{action} failed: {error}");
+            }
+
+            Ok(reply)
+        }
+    }
+
+    /// Apply the peer's offer/answer.
+    pub async fn set_remote_description(
+        &self,
+        description: &gst_webrtc::WebRTCSessionDescription,
+    ) -> Result<()> {
+        let reply = self.promise_action("set-remote-description", &[description]);
+        reply.await?;
+        Ok(())
+    }
+
+    /// Apply our own offer/answer (or a rollback).
+    pub async fn set_local_description(
+        &self,
+        description: &gst_webrtc::WebRTCSessionDescription,
+    ) -> Result<()> {
+        let reply = self.promise_action("set-local-description", &[description]);
+        reply.await?;
+        Ok(())
+    }
+
+    /// Create an answer to the remote offer.
+    pub async fn create_answer(&self) -> Result<gst_webrtc::WebRTCSessionDescription> {
+        let reply = self.promise_action("create-answer", &[&None::<gst::Structure>]);
+
+        // sometimes there is no answer in the answer :\
+        let reply = reply
+            .await?
+            .ok_or_else(|| anyhow!("local answer is empty"))?;
+        reply
+            .get::<gst_webrtc::WebRTCSessionDescription>("answer")
+            .map_err(|err| anyhow!("no answer in local answer {reply:?}: {err:?}"))
+    }
+
+    /// Create an offer, with webrtcbin offer options such as "ice-restart".
+    pub async fn create_offer(
+        &self,
+        options: Option<gst::Structure>,
+    ) -> Result<gst_webrtc::WebRTCSessionDescription> {
+        let reply = self.promise_action("create-offer", &[&options]);
+
+        let reply = reply
+            .await?
+            .ok_or_else(|| anyhow!("local offer is empty"))?;
+        reply
+            .get::<gst_webrtc::WebRTCSessionDescription>("offer")
+            .map_err(|err| anyhow!("no offer in local offer {reply:?}: {err:?}"))
+    }
+
+    /// Statistics for the whole session, or just for `pad`.
+    pub async fn get_stats(&self, pad: Option<&gst::Pad>) -> Result<gst::Structure> {
+        let pad = pad.cloned();
+        let reply = self.promise_action("get-stats", &[&pad]);
+
+        reply.await?.ok_or_else(|| anyhow!("no stats in reply"))
+    }
+
+    /// Add a remote ICE candidate. webrtcbin only queues it, there's no promise
+    /// telling whether it was usable before GStreamer 1.24 (add-ice-candidate-full).
+    pub fn add_ice_candidate(&self, line_index: u32, candidate: &str) {
+        self.webrtcbin
+            .emit_by_name::<()>("add-ice-candidate", &[&line_index, &candidate]);
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+    // negotiation
+
+    // negotiation started from a webrtcbin or GLib callback runs on the tokio runtime
+    fn spawn_negotiation<F, Fut>(&self, negotiate: F)
+    where
+        F: FnOnce(Session) -> Fut,
+        Fut: Future<Output = SessionResult<()>> + Send + 'static,
+    {
+        let session = self.clone();
+        let negotiation = negotiate(self.clone());
+        self.runtime.spawn(async move {
+            if let Err(err) = negotiation.await {
+                session.fail(err);
+            }
+        });
+    }
+
+    /// Create an offer and send it to the peer.
+    pub async fn make_offer(&self) -> SessionResult<()> {
+        self.make_offer_with_options(None).await
+    }
+
+    /// Restart ICE with fresh credentials, through a new offer.
+    pub async fn restart_ice(&self) -> SessionResult<()> {
+        let session_id = self.session_id();
+        println!("[WebRTC Session {session_id}] ICE RESTART");
+
+        *self
+            .ice_restarted
+            .lock()
+            .expect("failed to lock ICE restarted") = true;
+
+        let options = gst::Structure::builder("webrtcbin-offer-options")
+            .field("ice-restart", true)
+            .build();
+        self.make_offer_with_options(Some(options)).await
+    }
+
+    async fn make_offer_with_options(&self, options: Option<gst::Structure>) -> SessionResult<()> {
+        let session_id = self.session_id();
+        self.transition(SessionState::Negotiating)
+            .map_err(SessionError::Negotiation)?;
+        println!("[WebRTC Session {session_id}] CREATE OFFER {options:?}");
+
+        // cleared on have-local-offer, or here if there's no offer to collide with
+        *self
+            .making_offer
+            .lock()
+            .expect("failed to lock making offer") = true;
+
+        self.apply_codec_preferences(true);
+
+        let remote_offers = *self
+            .remote_offers
+            .lock()
+            .expect("failed to lock remote offers");
+        let offer = match self.create_offer(options).await {
+            Ok(offer) => offer,
+            Err(err) => {
+                *self
+                    .making_offer
+                    .lock()
+                    .expect("failed to lock making offer") = false;
+                return Err(SessionError::Negotiation(err));
+            }
+        };
+
+        let _negotiation = self.negotiation.lock().await;
+
+        // the peer's offer was taken while ours was being created, and answered
+        if *self
+            .remote_offers
+            .lock()
+            .expect("failed to lock remote offers")
+            != remote_offers
+        {
+            println!("[WebRTC Session {session_id}] remote offer taken meanwhile, dropping ours");
+            return Ok(());
+        }
+
+        self.apply_local_description(offer)
+            .await
+            .map_err(SessionError::Negotiation)
+    }
+
+    fn on_negotiation_needed(&self) -> Result<()> {
+        let session_id = self.session_id();
+        println!("[WebRTC Session {session_id}] NEGOTIATION NEEDED");
+
+        if self.options.backend_offers {
+            self.spawn_negotiation(|session| async move { session.make_offer().await });
+        }
+
+        Ok(())
+    }
+
+    pub async fn on_remote_offer(&self, offer: WebRtcData) -> SessionResult<()> {
+        let session_id = self.session_id();
+
+        let ret = gst_sdp::SDPMessage::parse_buffer(offer.sdp.as_bytes())
+            .map_err(|err| SessionError::SdpParse(anyhow!("offer: {err}")))?;
+
+        // ending sessions take no offers, crossed with ours or not
+        let state = self.state();
+        if state != SessionState::Negotiating && !state.can_become(SessionState::Negotiating) {
+            return Err(SessionError::UnexpectedDescription(anyhow!(
+                "offer rejected: session {session_id} is {state:?}"
+            )));
+        }
+
+        // any offer of ours being applied is in place by the time we have this
+        let _negotiation = self.negotiation.lock().await;
+
+        // perfect negotiation: did our offer cross with theirs?
+        let signaling_state = self
+            .webrtcbin
+            .property::<gst_webrtc::WebRTCSignalingState>("signaling-state");
+        let making_offer = *self
+            .making_offer
+            .lock()
+            .expect("failed to lock making offer");
+        let collision = making_offer || signaling_state != gst_webrtc::WebRTCSignalingState::Stable;
+
+        if collision {
+            let glare = format!(
+                "[WebRTC Session {session_id}] GLARE (making offer {making_offer}, {signaling_state:?})"
+            );
+            if !self.options.polite {
+                println!("{glare} - impolite, ignoring remote offer");
+                return Ok(());
+            }
+            println!("{glare} - polite, taking remote offer");
+        }
+
+        self.transition(SessionState::Negotiating)
+            .map_err(|err| SessionError::Negotiation(anyhow!("offer rejected: {err}")))?;
+
+        // a re-offer goes to the same webrtcbin, the pipeline is left running
+        if self
+            .webrtcbin
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>("current-remote-description")
+            .is_some()
+        {
+            println!("[WebRTC Session {session_id}] RENEGOTIATION");
+        }
+
+        self.update_media(&ret);
+
+        // webrtcbin only takes a rollback of an offer that's in place; one
+        // still being created is dropped once it sees this remote offer counted
+        if signaling_state == gst_webrtc::WebRTCSignalingState::HaveLocalOffer {
+            println!("[WebRTC Session {session_id}] rolling back local offer");
+            let rollback = gst_webrtc::WebRTCSessionDescription::new(
+                gst_webrtc::WebRTCSDPType::Rollback,
+                gst_sdp::SDPMessage::new(),
+            );
+            self.set_local_description(&rollback).await.map_err(|err| {
+                SessionError::Negotiation(anyhow!("failed to roll back local offer: {err}"))
+            })?;
+        }
+        *self
+            .making_offer
+            .lock()
+            .expect("failed to lock making offer") = false;
+        *self
+            .remote_offers
+            .lock()
+            .expect("failed to lock remote offers") += 1;
+
+        let offer =
+            gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Offer, ret);
+        self.set_remote_description(&offer)
+            .await
+            .map_err(SessionError::Negotiation)?;
+
+        // the offer's m-lines have transceivers now
+        self.apply_codec_preferences(false);
+
+        let answer = self
+            .create_answer_with_retries()
+            .await
+            .map_err(SessionError::Negotiation)?;
+        self.apply_local_description(answer)
+            .await
+            .map_err(SessionError::Negotiation)
+    }
+
+    // retry a missing or late answer a few times before giving up on the session
+    async fn create_answer_with_retries(&self) -> Result<gst_webrtc::WebRTCSessionDescription> {
+        let session_id = self.session_id();
+        let retries = self.options.create_answer_retries;
+        let timeout = self.options.negotiation_timeouts.create_answer;
+
+        let mut attempt = 0;
+        loop {
+            println!(
+                "[WebRTC Session {session_id}] CREATE ANSWER attempt {}",
+                attempt + 1
+            );
+
+            let result = match tokio::time::timeout(timeout, self.create_answer()).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("no reply after {timeout:?}")),
+            };
+
+            match result {
+                Ok(answer) => return Ok(answer),
+                Err(err) => {
+                    self.report_negotiation_failure(
+                        "create-answer",
+                        &format!("attempt {} of {}: {err:?}", attempt + 1, retries + 1),
+                    );
+                    if attempt == retries {
+                        return Err(err);
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    pub async fn on_remote_answer(&self, answer: WebRtcData) -> SessionResult<()> {
+        // only expected after our own offer
+        let state = self.state();
+        if state != SessionState::Negotiating {
+            return Err(SessionError::UnexpectedDescription(anyhow!(
+                "answer rejected: session {} is {state:?}",
+                self.session_id()
+            )));
+        }
+
+        let ret = gst_sdp::SDPMessage::parse_buffer(answer.sdp.as_bytes())
+            .map_err(|err| SessionError::SdpParse(anyhow!("answer: {err}")))?;
+
+        self.update_media(&ret);
+
+        let answer =
+            gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, ret);
+        self.set_remote_description(&answer)
+            .await
+            .map_err(SessionError::Negotiation)?;
+
+        self.log_negotiated_codecs(answer.sdp());
+        Ok(())
+    }
+
+    // codec-preferences of every audio/video transceiver from `codec_preferences`
+    fn apply_codec_preferences(&self, offer: bool) {
+        let session_id = self.session_id();
+        if self.options.codec_preferences.is_empty() {
+            return;
+        }
+
+        for transceiver in self.transceivers() {
+            let kind = match transceiver.property::<gst_webrtc::WebRTCKind>("kind") {
+                gst_webrtc::WebRTCKind::Audio => "audio",
+                gst_webrtc::WebRTCKind::Video => "video",
+                _ => continue,
+            };
+            let Some(caps) = media::codec_preferences(kind, &self.options.codec_preferences, offer)
+            else {
+                continue;
+            };
+
+            let mid = transceiver
+                .property::<Option<String>>("mid")
+                .unwrap_or_else(|| "?".to_string());
+            println!("[WebRTC Session {session_id}] CODEC PREFERENCES mid {mid} {kind}: {caps}");
+            transceiver.set_property("codec-preferences", &caps);
+        }
+    }
+
+    // the codec each m-line ended up with, the first format of the answer
+    fn log_negotiated_codecs(&self, answer: &gst_sdp::SDPMessageRef) {
+        let session_id = self.session_id();
+
+        for m in answer.medias() {
+            let mid = m.attribute_val("mid").unwrap_or("?");
+            let kind = m.media().unwrap_or("unknown");
+            if kind == "application" {
+                continue;
+            }
+            if m.port() == 0 {
+                println!("[WebRTC Session {session_id}] NEGOTIATED mid {mid} {kind}: rejected");
+                continue;
+            }
+            let Some(format) = m.formats().next() else {
+                continue;
+            };
+
+            let codec = format_attribute(m, "rtpmap", format).unwrap_or("unknown codec");
+            let fmtp = format_attribute(m, "fmtp", format)
+                .map(|fmtp| format!(" ({fmtp})"))
+                .unwrap_or_default();
+            println!(
+                "[WebRTC Session {session_id}] NEGOTIATED mid {mid} {kind}: {codec}{fmtp}, payload {format}"
+            );
+        }
+    }
+
+    // RTP from the peer on a new webrtcbin src pad
+    fn on_incoming_pad(&self, pad: &gst::Pad) -> Result<()> {
+        let session_id = self.session_id();
+        let track = IncomingTrack::from_pad(session_id, pad)?;
+        println!(
+            "[WebRTC Session {session_id}] TRACK RECEIVED mid {} {} {} {}",
+            track.mid, track.kind, track.codec, track.caps
+        );
+        self.send_event(SessionEvent::TrackReceived {
+            session_id,
+            mid: track.mid.clone(),
+            kind: track.kind.clone(),
+            codec: track.codec.clone(),
+            caps: track.caps.to_string(),
+        });
+
+        let sink = match track.kind.as_str() {
+            "video" => &self.options.video_receive,
+            "audio" => &self.options.audio_receive,
+            other => bail!("mid {} is {other}, expected audio or video", track.mid),
+        };
+
+        let receive_pad = match &self.options.record_dir {
+            Some(record_dir) => {
+                let (receive_pad, record_pad) = media::split_pad(&self.pipeline, pad)?;
+                let recording =
+                    media::add_recording(&self.pipeline, &record_pad, &track, record_dir)?;
+                self.recordings
+                    .lock()
+                    .expect("failed to lock recordings")
+                    .push(recording);
+                receive_pad
+            }
+            None => pad.clone(),
+        };
+
+        let branch = media::add_receive_branch(
+            &self.pipeline,
+            &receive_pad,
+            &track,
+            sink,
+            self.options.sample_callback.clone(),
+        )?;
+        // the tee, when recording; the recording itself is finished with the session
+        if let Some(tee) = receive_pad
+            .parent_element()
+            .filter(|tee| tee != &self.webrtcbin)
+        {
+            branch.add(tee);
+        }
+        self.receive_branches
+            .lock()
+            .expect("failed to lock receive branches")
+            .insert(pad.name().to_string(), branch);
+        Ok(())
+    }
+
+    // webrtcbin took away the src pad of a track, so take away what it fed
+    fn on_incoming_pad_removed(&self, pad: &gst::Pad) {
+        let session_id = self.session_id();
+        let Some(branch) = self
+            .receive_branches
+            .lock()
+            .expect("failed to lock receive branches")
+            .remove(pad.name().as_str())
+        else {
+            return;
+        };
+        println!(
+            "[WebRTC Session {session_id}] TRACK GONE mid {} from {}",
+            branch.mid,
+            pad.name()
+        );
+
+        if let Some(peer) = pad.peer() {
+            let _ = pad.unlink(&peer);
+        }
+        // state changes wait on streaming threads, which may be what's calling
+        self.pipeline
+            .call_async(move |pipeline| branch.remove(pipeline));
+    }
+
+    // pick up m-lines added or stopped by the peer's latest description
+    fn update_media(&self, sdp: &gst_sdp::SDPMessageRef) {
+        let session_id = self.session_id();
+        let mut media = self.media.lock().expect("failed to lock media");
+
+        for (line_index, m) in sdp.medias().enumerate() {
+            let line_index = line_index as u32;
+            let Some(mid) = m.attribute_val("mid") else {
+                continue;
+            };
+            let kind = m.media().unwrap_or("unknown");
+
+            // port 0 = transceiver stopped / m-line rejected
+            let stopped = m.port() == 0;
+
+            match (media.contains_key(mid), stopped) {
+                (false, false) => {
+                    println!("[WebRTC Session {session_id}] MEDIA ADDED mid {mid} {kind}");
+                    self.send_event(SessionEvent::TrackAdded {
+                        session_id,
+                        mid: mid.to_string(),
+                        kind: kind.to_string(),
+                    });
+                    media.insert(
+                        mid.to_string(),
+                        Media {
+                            line_index,
+                            kind: kind.to_string(),
+                        },
+                    );
+                }
+                (true, true) => {
+                    println!("[WebRTC Session {session_id}] MEDIA STOPPED mid {mid} {kind}");
+                    self.send_event(SessionEvent::TrackRemoved {
+                        session_id,
+                        mid: mid.to_string(),
+                    });
+                    media.remove(mid);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn on_remote_ice_candidate(
+        &self,
+        ice_candidate_data: IceCandidateWebRtcData,
+    ) -> SessionResult<()> {
+        let state = self.state();
+        if matches!(state, SessionState::Ending | SessionState::Ended) {
+            return Err(SessionError::Ice(anyhow!(
+                "candidate rejected: session {} is {state:?}",
+                self.session_id()
+            )));
+        }
+
+        let IceCandidateWebRtcData {
+            candidate,
+            media_id,
+            line_index,
+            ..
+        } = ice_candidate_data;
+        let session_id = self.session_id();
 
-        if connection_state == gst_webrtc::WebRTCPeerConnectionState::Failed {
-            println!("[WebRTC Session {session_id}] CONNECTION FAILED, ending session");
-            self.stop()?;
+        let line_index = line_index.or_else(|| self.remote_line_index(media_id.as_deref()?));
+
+        // end-of-candidates; webrtcbin takes an empty candidate for it
+        if candidate.is_empty() {
+            let line_indexes = match line_index {
+                Some(line_index) => vec![line_index],
+                // for the whole session
+                None => (0..self.remote_media_count()).collect(),
+            };
+            println!(
+                "[WebRTC Session {session_id}] END OF REMOTE CANDIDATES m-lines {line_indexes:?}"
+            );
+            for line_index in line_indexes {
+                self.add_ice_candidate(line_index, "");
+            }
+            return Ok(());
+        }
+
+        let Some(candidate) =
+            self.filter_candidate("remote", &self.options.remote_candidate_filter, &candidate)
+        else {
+            return Ok(());
+        };
+
+        let Some(line_index) = line_index else {
+            println!(
+                "[WebRTC Session {session_id}] candidate for unknown mid {media_id:?}, dropped: {candidate}"
+            );
+            return Ok(());
+        };
+
+        let mdns_name = candidate_address(&candidate).filter(|address| address.ends_with(".local"));
+        if let (Some(name), Some(server)) = (mdns_name, self.options.mdns_server) {
+            // resolving can take a while, don't hold up other signals
+            let name = name.to_string();
+            self.spawn_negotiation(move |session| async move {
+                session
+                    .add_mdns_candidate(line_index, candidate, name, server)
+                    .await
+            });
+            return Ok(());
+        }
+
+        self.add_ice_candidate(line_index, &candidate);
+        Ok(())
+    }
+
+    // add a candidate with its "<uuid>.local" address resolved
+    async fn add_mdns_candidate(
+        &self,
+        line_index: u32,
+        candidate: String,
+        name: String,
+        server: SocketAddr,
+    ) -> SessionResult<()> {
+        let session_id = self.session_id();
+
+        let address = mdns::resolve(&name, server, self.options.mdns_timeout)
+            .await
+            .map_err(|err| {
+                SessionError::Ice(err.context(format!("unresolvable mDNS candidate {candidate}")))
+            })?;
+        println!("[WebRTC Session {session_id}] mDNS {name} → {address}");
+
+        let candidate = with_candidate_address(&candidate, &address.to_string());
+        self.add_ice_candidate(line_index, &candidate);
+        Ok(())
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+
+    fn on_connection_state_changed(&self) -> Result<()> {
+        let connection_state = self
+            .webrtcbin
+            .property::<gst_webrtc::WebRTCPeerConnectionState>("connection-state");
+
+        let session_id = self.session_id();
+        println!("[WebRTC Session {session_id}] CONNECTION STATE → {connection_state:?}");
+        self.send_event(SessionEvent::ConnectionStateChanged {
+            session_id,
+            state: connection_state,
+        });
+
+        match connection_state {
+            gst_webrtc::WebRTCPeerConnectionState::Connected => {
+                self.try_transition(SessionState::Connected);
+            }
+            gst_webrtc::WebRTCPeerConnectionState::Failed => {
+                let ice_restarted = *self
+                    .ice_restarted
+                    .lock()
+                    .expect("failed to lock ICE restarted");
+                if ice_restarted {
+                    println!("[WebRTC Session {session_id}] CONNECTION FAILED, ending session");
+                    self.stop()?;
+                } else {
+                    println!("[WebRTC Session {session_id}] CONNECTION FAILED, trying ICE restart");
+                    self.spawn_negotiation(|session| async move { session.restart_ice().await });
+                }
+            }
+            gst_webrtc::WebRTCPeerConnectionState::Closed => {
+                println!("[WebRTC Session {session_id}] CONNECTION CLOSED, ending session");
+                self.stop()?;
+            }
+            _ => {}
         }
 
         Ok(())
@@ -445,6 +1806,87 @@ impl Session {
 
         let session_id = self.session_id();
         println!("[WebRTC Session {session_id}] ICE CONNECTION STATE → {ice_connection_state:?}");
+        self.send_event(SessionEvent::IceConnectionStateChanged {
+            session_id,
+            state: ice_connection_state,
+        });
+
+        match ice_connection_state {
+            gst_webrtc::WebRTCICEConnectionState::Disconnected => {
+                if self.state() == SessionState::Connected {
+                    self.try_transition(SessionState::Reconnecting);
+                }
+                self.schedule_ice_restart();
+            }
+            gst_webrtc::WebRTCICEConnectionState::Connected
+            | gst_webrtc::WebRTCICEConnectionState::Completed => {
+                // a later disconnection gets its own restart
+                *self
+                    .ice_restarted
+                    .lock()
+                    .expect("failed to lock ICE restarted") = false;
+
+                if self.state() == SessionState::Reconnecting {
+                    self.try_transition(SessionState::Connected);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // restart ICE if it's still disconnected after the grace period
+    fn schedule_ice_restart(&self) {
+        let session_id = self.session_id();
+        let grace = self.options.ice_disconnected_grace;
+        println!(
+            "[WebRTC Session {session_id}] ICE DISCONNECTED, restart in {grace:?} if still down"
+        );
+
+        let session_clone = self.downgrade();
+        glib::timeout_add_once(grace, move || {
+            let session = upgrade_weak!(session_clone);
+
+            let ice_connection_state = session
+                .webrtcbin
+                .property::<gst_webrtc::WebRTCICEConnectionState>("ice-connection-state");
+            let ice_restarted = *session
+                .ice_restarted
+                .lock()
+                .expect("failed to lock ICE restarted");
+
+            if ice_connection_state == gst_webrtc::WebRTCICEConnectionState::Disconnected
+                && !ice_restarted
+            {
+                session.spawn_negotiation(|session| async move { session.restart_ice().await });
+            }
+        });
+    }
+
+    fn on_ice_gathering_state_changed(&self) -> Result<()> {
+        let ice_gathering_state = self
+            .webrtcbin
+            .property::<gst_webrtc::WebRTCICEGatheringState>("ice-gathering-state");
+
+        let session_id = self.session_id();
+        println!("[WebRTC Session {session_id}] ICE GATHERING STATE → {ice_gathering_state:?}");
+        self.send_event(SessionEvent::IceGatheringStateChanged {
+            session_id,
+            state: ice_gathering_state,
+        });
+
+        if ice_gathering_state == gst_webrtc::WebRTCICEGatheringState::Gathering {
+            self.watch_ice_gathering();
+        }
+
+        if ice_gathering_state == gst_webrtc::WebRTCICEGatheringState::Complete {
+            if self.options.trickle_ice {
+                self.on_local_ice_gathering_complete()?;
+            } else {
+                self.send_held_local_description()?;
+            }
+        }
 
         Ok(())
     }
@@ -456,6 +1898,32 @@ impl Session {
 
         let session_id = self.session_id;
         println!("[WebRTC Session {session_id}] SIGNALING STATE → {signaling_state:?}");
+        self.send_event(SessionEvent::SignalingStateChanged {
+            session_id,
+            state: signaling_state,
+        });
+
+        // offer/answer done, renegotiations may already be connected
+        if signaling_state == gst_webrtc::WebRTCSignalingState::Stable
+            && self.state() == SessionState::Negotiating
+        {
+            let connection_state = self
+                .webrtcbin
+                .property::<gst_webrtc::WebRTCPeerConnectionState>("connection-state");
+            if connection_state == gst_webrtc::WebRTCPeerConnectionState::Connected {
+                self.try_transition(SessionState::Connected);
+            } else {
+                self.try_transition(SessionState::Connecting);
+            }
+        }
+
+        // our offer is now in place, so a remote offer will show up as a collision
+        if signaling_state == gst_webrtc::WebRTCSignalingState::HaveLocalOffer {
+            *self
+                .making_offer
+                .lock()
+                .expect("failed to lock making offer") = false;
+        }
 
         Ok(())
     }
@@ -463,44 +1931,37 @@ impl Session {
     //////////////////////////////////////////////////////////////////////////
 
     fn on_data_channel(&self, data_channel: gst_webrtc::WebRTCDataChannel) -> Result<()> {
+        let session_id = self.session_id();
         let label = data_channel.property::<String>("label");
-        println!("[WebRTC Session] DATA CHANNEL {label}");
-
-        // // open
-        // let session_clone = self.downgrade();
-        // data_channel.connect("on-open", false, move |values| {
-        //     let session = upgrade_weak!(session_clone, None);
-
-        //     let data_channel = values[0]
-        //         .get::<gst_webrtc::WebRTCDataChannel>()
-        //         .expect("Invalid argument");
-        //     if let Err(err) = session.on_data_channel_open(data_channel) {
-        //         gst::element_error!(
-        //             session.pipeline,
-        //             gst::LibraryError::Failed,
-        //             ("failed to handle data channel open: {:?}", err)
-        //         );
-        //     }
-        //     None
-        // });
+        println!("[WebRTC Session {session_id}] DATA CHANNEL {label}");
 
-        // // close
-        // let session_clone = self.downgrade();
-        // data_channel.connect("on-close", false, move |values| {
-        //     let session = upgrade_weak!(session_clone, None);
+        // open
+        let session_clone = self.downgrade();
+        data_channel.connect("on-open", false, move |values| {
+            let session = upgrade_weak!(session_clone, None);
+
+            let data_channel = values[0]
+                .get::<gst_webrtc::WebRTCDataChannel>()
+                .expect("Invalid argument");
+            if let Err(err) = session.on_data_channel_open(data_channel) {
+                session.fail(SessionError::DataChannel(err));
+            }
+            None
+        });
 
-        //     let data_channel = values[0]
-        //         .get::<gst_webrtc::WebRTCDataChannel>()
-        //         .expect("Invalid argument");
-        //     if let Err(err) = session.on_data_channel_close(data_channel) {
-        //         gst::element_error!(
-        //             session.pipeline,
-        //             gst::LibraryError::Failed,
-        //             ("failed to handle data channel close: {:?}", err)
-        //         );
-        //     }
-        //     None
-        // });
+        // close
+        let session_clone = self.downgrade();
+        data_channel.connect("on-close", false, move |values| {
+            let session = upgrade_weak!(session_clone, None);
+
+            let data_channel = values[0]
+                .get::<gst_webrtc::WebRTCDataChannel>()
+                .expect("Invalid argument");
+            if let Err(err) = session.on_data_channel_close(data_channel) {
+                session.fail(SessionError::DataChannel(err));
+            }
+            None
+        });
 
         // // text message
         // let session_clone = self.downgrade();
@@ -543,83 +2004,47 @@ impl Session {
         //     None
         // });
 
-        // // error
-        // let session_clone = self.downgrade();
-        // data_channel.connect("on-error", false, move |values| {
-        //     let session = upgrade_weak!(session_clone, None);
+        // error
+        let session_clone = self.downgrade();
+        data_channel.connect("on-error", false, move |values| {
+            let session = upgrade_weak!(session_clone, None);
 
-        //     let data_channel = values[0]
-        //         .get::<gst_webrtc::WebRTCDataChannel>()
-        //         .expect("Invalid argument");
+            let data_channel = values[0]
+                .get::<gst_webrtc::WebRTCDataChannel>()
+                .expect("Invalid argument");
 
-        //     let error = values[1].get::<glib::Error>().expect("Invalid argument");
+            let error = values[1].get::<glib::Error>().expect("Invalid argument");
 
-        //     if let Err(err) = session.on_data_channel_error(data_channel, error) {
-        //         gst::element_error!(
-        //             session.pipeline,
-        //             gst::LibraryError::Failed,
-        //             ("failed to handle data channel error: {:?}", err)
-        //         );
-        //     }
-        //     None
-        // });
+            if let Err(err) = session.on_data_channel_error(data_channel, error) {
+                session.fail(SessionError::DataChannel(err));
+            }
+            None
+        });
 
         Ok(())
     }
 
-    // fn on_data_channel_open(&self, data_channel: gst_webrtc::WebRTCDataChannel) -> Result<()> {
-    //     if self.is_running() {
-    //         let session_id = self.session_id();
-    //         let label = data_channel.property::<String>("label");
-    //         debug!("[WebRTC Session {session_id}] DATA CHANNEL {label} OPEN");
-
-    //         let data_channel = DataChannel::new(&label, data_channel);
-    //         let mut m = self
-    //             .data_channels_by_label
-    //             .write()
-    //             .expect("failed to lock data channel map for write");
-
-    //         let data_channel = Arc::new(Mutex::new(data_channel));
-
-    //         m.insert(label.clone(), data_channel.clone());
+    fn on_data_channel_open(&self, data_channel: gst_webrtc::WebRTCDataChannel) -> Result<()> {
+        let session_id = self.session_id();
+        let label = data_channel.property::<String>("label");
+        println!("[WebRTC Session {session_id}] DATA CHANNEL {label} OPEN");
 
-    //         // notify listener
-    //         self.session_event_tx
-    //             .send(SessionEvent::DataChannelOpen {
-    //                 session: self.downgrade(),
-    //                 label,
-    //             })
-    //             .expect("failed to send data channel open event");
+        // notify listener
+        self.send_event(SessionEvent::DataChannelOpen { session_id, label });
 
-    //         let dc: tokio::sync::MutexGuard<'_, DataChannel> = data_channel.blocking_lock();
-    //         dc.start_send_thread(data_channel.clone());
-    //     }
+        Ok(())
+    }
 
-    //     Ok(())
-    // }
+    fn on_data_channel_close(&self, data_channel: gst_webrtc::WebRTCDataChannel) -> Result<()> {
+        let session_id = self.session_id();
+        let label = data_channel.property::<String>("label");
+        println!("[WebRTC Session {session_id}] DATA CHANNEL {label} CLOSE");
 
-    // fn on_data_channel_close(&self, data_channel: gst_webrtc::WebRTCDataChannel) -> Result<()> {
-    //     if self.is_running() {
-    //         let session_id = self.session_id();
-    //         let label = data_channel.property::<String>("label");
-    //         info!("[WebRTC Session {session_id}] DATA CHANNEL {label} CLOSE");
+        // notify listener
+        self.send_event(SessionEvent::DataChannelClosed { session_id, label });
 
-    //         let mut m = self
-    //             .data_channels_by_label
-    //             .write()
-    //             .expect("failed to lock data channel map for write");
-    //         m.remove(&label);
-
-    //         // notify listener
-    //         self.session_event_tx
-    //             .send(SessionEvent::DataChannelClosed {
-    //                 session: self.downgrade(),
-    //                 label,
-    //             })
-    //             .expect("failed to send data channel closed event");
-    //     }
-    //     Ok(())
-    // }
+        Ok(())
+    }
 
     // fn on_data_channel_message_string(
     //     &self,
@@ -694,28 +2119,429 @@ impl Session {
     //     Ok(())
     // }
 
-    // fn on_data_channel_error(
-    //     &self,
-    //     data_channel: gst_webrtc::WebRTCDataChannel,
-    //     error: glib::Error,
-    // ) -> Result<()> {
-    //     if self.is_running() {
-    //         let session_id = self.session_id();
-    //         let label = data_channel.property::<String>("label");
-    //         error!("[WebRTC Session {session_id}] DATA CHANNEL {label} ERROR - {error}");
-
-    //         // notify listener
-    //         let error_message = format!("{error}");
-    //         self.session_event_tx
-    //             .send(SessionEvent::DataChannelError {
-    //                 session: self.downgrade(),
-    //                 label,
-    //                 error_message,
-    //             })
-    //             .expect("failed to send data channel error event");
-    //     }
-    //     Ok(())
-    // }
+    fn on_data_channel_error(
+        &self,
+        data_channel: gst_webrtc::WebRTCDataChannel,
+        error: glib::Error,
+    ) -> Result<()> {
+        let session_id = self.session_id();
+        let label = data_channel.property::<String>("label");
+        println!("[WebRTC Session {session_id}] DATA CHANNEL {label} ERROR - {error}");
+
+        // notify listener
+        let error_message = format!("{error}");
+        self.send_event(SessionEvent::DataChannelError {
+            session_id,
+            label,
+            error_message,
+        });
+
+        Ok(())
+    }
 
     //////////////////////////////////////////////////////////////////////////
 }
+
+// webrtcbin takes a single STUN server, then a first TURN server and any number more
+fn configure_ice_servers(
+    session_id: u64,
+    webrtcbin: &gst::Element,
+    ice_servers: &IceServers,
+) -> SessionResult<()> {
+    let mut stun_servers = ice_servers.webrtcbin_stun_servers().into_iter();
+    if let Some(uri) = stun_servers.next() {
+        println!("[WebRTC Session {session_id}] STUN server {uri}");
+        webrtcbin.set_property_from_str("stun-server", &uri);
+    }
+    for uri in stun_servers {
+        println!(
+            "[WebRTC Session {session_id}] webrtcbin only takes one STUN server, {uri} ignored"
+        );
+    }
+
+    let mut turn_servers = ice_servers.webrtcbin_turn_servers().into_iter();
+    if let Some(uri) = turn_servers.next() {
+        println!(
+            "[WebRTC Session {session_id}] TURN server {}",
+            without_credentials(&uri)
+        );
+        webrtcbin.set_property_from_str("turn-server", &uri);
+    }
+    for uri in turn_servers {
+        println!(
+            "[WebRTC Session {session_id}] TURN server {}",
+            without_credentials(&uri)
+        );
+        if !webrtcbin.emit_by_name::<bool>("add-turn-server", &[&uri]) {
+            return Err(SessionError::Ice(anyhow!(
+                "webrtcbin rejected TURN server {}",
+                without_credentials(&uri)
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// protocols, port range and local addresses of webrtcbin's libnice agent
+fn configure_ice_agent(
+    session_id: u64,
+    webrtcbin: &gst::Element,
+    options: &IceAgentOptions,
+) -> SessionResult<()> {
+    let ice_agent = webrtcbin.property::<glib::Object>("ice-agent");
+
+    if *options != IceAgentOptions::default() {
+        println!(
+            "[WebRTC Session {session_id}] ICE agent tcp {} udp {} ports {}-{}",
+            options.tcp, options.udp, options.min_port, options.max_port
+        );
+    }
+    ice_agent.set_property("ice-tcp", options.tcp);
+    ice_agent.set_property("ice-udp", options.udp);
+    ice_agent.set_property("min-rtp-port", u32::from(options.min_port));
+    ice_agent.set_property("max-rtp-port", u32::from(options.max_port));
+
+    // adding any address turns off the agent's own interface discovery
+    let addresses = options.local_addresses().map_err(SessionError::Ice)?;
+    for address in addresses.unwrap_or_default() {
+        println!("[WebRTC Session {session_id}] ICE agent gathering on {address}");
+        if !ice_agent.emit_by_name::<bool>("add-local-ip-address", &[&address.to_string()]) {
+            return Err(SessionError::Ice(anyhow!(
+                "ICE agent rejected local address {address}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// value of an "a=<key>:<format> <value>" attribute, e.g. "VP8/90000" for rtpmap
+fn format_attribute<'a>(
+    media: &'a gst_sdp::SDPMediaRef,
+    key: &str,
+    format: &str,
+) -> Option<&'a str> {
+    media
+        .attributes()
+        .filter(|attribute| attribute.key() == key)
+        .filter_map(|attribute| attribute.value())
+        .find_map(|value| value.strip_prefix(format)?.strip_prefix(' '))
+}
+
+// host:port, for logging
+fn without_credentials(uri: &str) -> &str {
+    uri.rsplit_once('@')
+        .map_or(uri, |(_credentials, host)| host)
+}
+
+// "ufrag" extension attribute of a candidate line (RFC 8839), if present
+fn candidate_username_fragment(candidate: &str) -> Option<String> {
+    let mut parts = candidate.split_whitespace();
+    while let Some(part) = parts.next() {
+        if part == "ufrag" {
+            return parts.next().map(String::from);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // a session with the channels it talks through kept open
+    struct TestSession {
+        session: Session,
+        signals: SignalReceiver,
+        _ended: mpsc::UnboundedReceiver<u64>,
+        _events: mpsc::UnboundedReceiver<SessionEvent>,
+    }
+
+    impl TestSession {
+        fn start(session_id: u64, options: SessionOptions) -> Self {
+            gst::init().expect("failed to init GStreamer");
+
+            let (signal_tx, signals) = mpsc::unbounded_channel();
+            let (ended_tx, _ended) = mpsc::unbounded_channel();
+            let (event_tx, _events) = mpsc::unbounded_channel();
+            let session =
+                Session::start(session_id, options, Arc::new(signal_tx), ended_tx, event_tx)
+                    .expect("failed to start session");
+
+            Self {
+                session,
+                signals,
+                _ended,
+                _events,
+            }
+        }
+
+        // offers a data channel as soon as it starts
+        fn offering(session_id: u64, polite: bool) -> Self {
+            Self::start(
+                session_id,
+                SessionOptions {
+                    backend_offers: true,
+                    polite,
+                    ..Default::default()
+                },
+            )
+        }
+
+        // our offer's SDP, once it's our local description
+        async fn local_offer(&mut self) -> String {
+            let sdp = tokio::time::timeout(TIMEOUT, async {
+                loop {
+                    match self.signals.recv().await {
+                        Some(Signal::WebRtcOffer { data }) => break data.webrtc_data.sdp,
+                        Some(_) => continue,
+                        None => panic!("signals closed before an offer"),
+                    }
+                }
+            })
+            .await
+            .expect("no offer");
+
+            self.wait_for_signaling_state(gst_webrtc::WebRTCSignalingState::HaveLocalOffer)
+                .await;
+            sdp
+        }
+
+        async fn wait_for_signaling_state(&self, state: gst_webrtc::WebRTCSignalingState) {
+            tokio::time::timeout(TIMEOUT, async {
+                while self.signaling_state() != state {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("never got to {state:?}, at {:?}", self.signaling_state()));
+        }
+
+        fn signaling_state(&self) -> gst_webrtc::WebRTCSignalingState {
+            self.session
+                .webrtcbin
+                .property::<gst_webrtc::WebRTCSignalingState>("signaling-state")
+        }
+
+        fn sent_answer(&mut self) -> bool {
+            std::iter::from_fn(|| self.signals.try_recv().ok())
+                .any(|signal| matches!(signal, Signal::WebRtcAnswer { .. }))
+        }
+
+        // offers and answers sent so far, in order
+        fn sent_descriptions(&mut self) -> Vec<&'static str> {
+            std::iter::from_fn(|| self.signals.try_recv().ok())
+                .filter_map(|signal| match signal {
+                    Signal::WebRtcOffer { .. } => Some("offer"),
+                    Signal::WebRtcAnswer { .. } => Some("answer"),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    // each side has made an offer, and ours gets theirs
+    async fn glare(polite: bool) -> (TestSession, SessionResult<()>) {
+        let mut ours = TestSession::offering(1, polite);
+        let mut theirs = TestSession::offering(2, !polite);
+        ours.local_offer().await;
+        let offer = theirs.local_offer().await;
+
+        let result = offer_to(&ours.session, offer).await;
+        (ours, result)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn polite_session_rolls_back_and_answers_crossed_offer() {
+        let (mut ours, result) = glare(true).await;
+
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(
+            ours.signaling_state(),
+            gst_webrtc::WebRTCSignalingState::Stable
+        );
+        assert!(ours.sent_answer());
+        assert!(!*ours.session.making_offer.lock().unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn impolite_session_ignores_crossed_offer() {
+        let (mut ours, result) = glare(false).await;
+
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(
+            ours.signaling_state(),
+            gst_webrtc::WebRTCSignalingState::HaveLocalOffer
+        );
+        assert!(!ours.sent_answer());
+        assert!(ours
+            .session
+            .webrtcbin
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>("pending-remote-description")
+            .is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn polite_session_drops_offer_still_being_created() {
+        let mut ours = TestSession::start(
+            1,
+            SessionOptions {
+                polite: true,
+                ..Default::default()
+            },
+        );
+        let mut theirs = TestSession::offering(2, false);
+        let offer = theirs.local_offer().await;
+
+        // as far as it goes before webrtcbin replies with our offer
+        let session = ours.session.clone();
+        let making_offer = async move { session.make_offer().await };
+        tokio::pin!(making_offer);
+        assert!(tokio::time::timeout(Duration::ZERO, &mut making_offer)
+            .await
+            .is_err());
+        assert!(*ours.session.making_offer.lock().unwrap());
+        assert_eq!(
+            ours.signaling_state(),
+            gst_webrtc::WebRTCSignalingState::Stable
+        );
+
+        let result = offer_to(&ours.session, offer).await;
+        assert!(result.is_ok(), "{result:?}");
+
+        let result = tokio::time::timeout(TIMEOUT, making_offer)
+            .await
+            .expect("offer never finished");
+        assert!(result.is_ok(), "{result:?}");
+
+        // answered, and the stale offer never applied or sent
+        assert_eq!(
+            ours.signaling_state(),
+            gst_webrtc::WebRTCSignalingState::Stable
+        );
+        assert_eq!(ours.sent_descriptions(), vec!["answer"]);
+    }
+
+    async fn offer_to(session: &Session, sdp: String) -> SessionResult<()> {
+        session
+            .on_remote_offer(WebRtcData {
+                data_type: Some("offer".to_string()),
+                sdp,
+            })
+            .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn offer_to_ended_session_is_rejected() {
+        for polite in [true, false] {
+            let mut ours = TestSession::offering(1, polite);
+            let mut theirs = TestSession::offering(2, !polite);
+            ours.local_offer().await;
+            let offer = theirs.local_offer().await;
+            ours.session.stop().expect("failed to stop");
+
+            let result = offer_to(&ours.session, offer).await;
+
+            assert!(
+                matches!(result, Err(SessionError::UnexpectedDescription(_))),
+                "polite {polite}: {result:?}"
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stray_answer_is_rejected_without_ending_session() {
+        let ours = TestSession::start(1, SessionOptions::default());
+        let mut theirs = TestSession::offering(2, false);
+        let sdp = theirs.local_offer().await;
+
+        let result = ours
+            .session
+            .on_remote_answer(WebRtcData {
+                data_type: Some("answer".to_string()),
+                sdp,
+            })
+            .await;
+
+        match result {
+            Err(err @ SessionError::UnexpectedDescription(_)) => assert!(!err.is_fatal()),
+            other => panic!("{other:?}"),
+        }
+        assert_eq!(ours.session.state(), SessionState::New);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remote_candidate_by_mid_alone_finds_its_m_line() {
+        let ours = TestSession::start(1, SessionOptions::default());
+        let mut theirs = TestSession::offering(2, false);
+        let offer = theirs.local_offer().await;
+        let mid = offer
+            .lines()
+            .find_map(|line| line.strip_prefix("a=mid:"))
+            .expect("no mid in the offer")
+            .to_string();
+
+        let result = offer_to(&ours.session, offer).await;
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(ours.session.remote_line_index(&mid), Some(0));
+        assert_eq!(ours.session.remote_line_index("nope"), None);
+
+        let candidate = |candidate: &str, media_id: Option<&str>| IceCandidateWebRtcData {
+            candidate: candidate.to_string(),
+            media_id: media_id.map(String::from),
+            line_index: None,
+            username_fragment: None,
+        };
+        for data in [
+            candidate(
+                "candidate:1 1 UDP 2122252543 127.0.0.1 40000 typ host",
+                Some(&mid),
+            ),
+            // end-of-candidates, for the m-line and for everything
+            candidate("", Some(&mid)),
+            candidate("", None),
+        ] {
+            let result = ours.session.on_remote_ice_candidate(data).await;
+            assert!(result.is_ok(), "{result:?}");
+        }
+    }
+
+    #[test]
+    fn session_state_transitions() {
+        use SessionState::*;
+
+        let allowed = [
+            (New, Negotiating),
+            (Negotiating, Connecting),
+            (Negotiating, Connected),
+            (Connecting, Connected),
+            (Connected, Reconnecting),
+            (Reconnecting, Connected),
+            (Connecting, Negotiating),
+            (Connected, Negotiating),
+            (Reconnecting, Negotiating),
+            (Ending, Ended),
+        ];
+        let all = [
+            New,
+            Negotiating,
+            Connecting,
+            Connected,
+            Reconnecting,
+            Ending,
+            Ended,
+        ];
+
+        for from in all {
+            for to in all {
+                // anything still alive can end
+                let expected = allowed.contains(&(from, to))
+                    || (to == Ending && !matches!(from, Ending | Ended));
+                assert_eq!(from.can_become(to), expected, "{from:?} → {to:?}");
+            }
+        }
+    }
+}
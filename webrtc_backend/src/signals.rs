@@ -1,18 +1,25 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
+use anyhow::{Context, Result};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tungstenite::Message;
 use url::Url;
 
+use crate::ice_servers::{IceServer, IceServers};
+
 pub type SignalSender = mpsc::UnboundedSender<Signal>;
 pub type SignalReceiver = mpsc::UnboundedReceiver<Signal>;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Signal {
+    /// Peer asks us to start a session and send it an offer.
+    #[serde(rename = "session_request")]
+    SessionRequest { data: SessionRequestData },
+
     #[serde(rename = "webrtc_offer")]
     WebRtcOffer { data: WebRtcOfferData },
 
@@ -21,6 +28,39 @@ pub enum Signal {
 
     #[serde(rename = "ice_candidate")]
     IceCandidate { data: IceCandidateData },
+
+    /// Peer asks us to restart ICE, we send a new offer with fresh credentials.
+    #[serde(rename = "ice_restart")]
+    IceRestart { data: IceRestartData },
+
+    /// Either side is ending the session.
+    #[serde(rename = "bye")]
+    Bye { data: ByeData },
+
+    /// Peer asks which STUN/TURN servers to use before creating its peer connection.
+    #[serde(rename = "ice_servers_request")]
+    IceServersRequest { data: IceServersRequestData },
+
+    /// The STUN/TURN servers we use, for the peer to use too.
+    #[serde(rename = "ice_servers")]
+    IceServers { data: IceServersData },
+
+    /// The signal server runs a STUN server (signal_server --stun).
+    #[serde(rename = "stun_server")]
+    StunServer { data: StunServerData },
+
+    /// The signal server runs a TURN server (signal_server --turn).
+    #[serde(rename = "turn_server")]
+    TurnServer { data: IceServer },
+
+    /// Something went wrong in the session, `fatal` if it's ending because of it.
+    #[serde(rename = "error")]
+    Error { data: ErrorData },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionRequestData {
+    pub session_id: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -49,6 +89,42 @@ pub struct IceCandidateData {
     pub webrtc_data: IceCandidateWebRtcData,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IceRestartData {
+    pub session_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ByeData {
+    pub session_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IceServersRequestData {
+    pub session_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IceServersData {
+    pub session_id: u64,
+    pub ice_servers: IceServers,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StunServerData {
+    // "stun:host:port"
+    pub urls: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorData {
+    pub session_id: u64,
+    // e.g. "sdp_parse", "negotiation", "unexpected_description", "ice", "pipeline", "data_channel"
+    pub code: String,
+    pub message: String,
+    pub fatal: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IceCandidateWebRtcData {
     pub candidate: String,
@@ -72,36 +148,71 @@ pub async fn connect(
     url: &str,
     incoming_signals_tx: SignalSender,
     mut outgoing_signals_rx: SignalReceiver,
-) {
+) -> Result<tokio::task::JoinHandle<()>> {
     println!("[Signals] connecting to signal server {url}");
 
-    let url = Url::parse(url).unwrap();
-    let (socket, _response) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let url = Url::parse(url).with_context(|| format!("invalid signal server URL {url}"))?;
+    let (socket, _response) = tokio_tungstenite::connect_async(&url)
+        .await
+        .with_context(|| format!("failed to connect to signal server {url}"))?;
 
     println!("[Signals] CONNECTED");
 
     let (mut ws_write, mut ws_read) = socket.split();
 
-    let _task = tokio::spawn(async move {
+    Ok(tokio::spawn(async move {
         loop {
             tokio::select! {
                 Some(msg) = ws_read.next() => {
-                    match msg.unwrap() {
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            println!("[Signals] connection lost: {err}");
+                            break;
+                        }
+                    };
+                    match msg {
                         Message::Text(text) => {
-                            println!("[Signals] RECEIVE {text}");
-                            let signal: Signal = serde_json::from_str(&text).unwrap();
-                            incoming_signals_tx.send(signal).unwrap();
+                            // one bad message from a peer shouldn't take signaling down
+                            let signal: Signal = match serde_json::from_str(&text) {
+                                Ok(signal) => signal,
+                                Err(err) => {
+                                    println!("[Signals] unrecognised signal, dropped: {err}");
+                                    continue;
+                                }
+                            };
+                            // parsed rather than raw, so TURN credentials are redacted
+                            println!("[Signals] RECEIVE {signal:?}");
+                            if incoming_signals_tx.send(signal).is_err() {
+                                // nobody listening, we're shutting down
+                                break;
+                            }
                         }
                         _ => {}
                     }
 
                 }
-                Some(signal) = outgoing_signals_rx.recv() => {
-                    let text = serde_json::to_string(&signal).unwrap();
-                    println!("[Signals] SEND {text}");
-                    ws_write.send(Message::Text(text)).await.unwrap();
+                signal = outgoing_signals_rx.recv() => {
+                    let Some(signal) = signal else {
+                        // every sender dropped, we're shutting down
+                        println!("[Signals] CLOSE");
+                        let _ = ws_write.close().await;
+                        break;
+                    };
+                    let text = match serde_json::to_string(&signal) {
+                        Ok(text) => text,
+                        Err(err) => {
+                            println!("[Signals] failed to serialize {signal:?}: {err}");
+                            continue;
+                        }
+                    };
+                    println!("[Signals] SEND {signal:?}");
+                    if let Err(err) = ws_write.send(Message::Text(text)).await {
+                        println!("[Signals] connection lost: {err}");
+                        break;
+                    }
                 }
             }
         }
-    });
+    }))
 }
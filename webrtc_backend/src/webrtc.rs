@@ -1,46 +1,373 @@
 use anyhow::Result;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
 
-use crate::session::Session;
+use crate::events::*;
+use crate::ice_servers::IceServer;
+use crate::session::{Session, SessionOptions};
 use crate::signals::*;
 
+// how long each session gets to reach EOS on shutdown
+const SHUTDOWN_EOS_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub struct WebRtc {
-    session: Arc<Mutex<Session>>,
-    // outgoing_signal_tx: Arc<SignalSender>,
+    main_loop: glib::MainLoop,
+    // for new sessions, the signal server may add its STUN server
+    session_options: StdMutex<SessionOptions>,
+    outgoing_signal_tx: Arc<SignalSender>,
+    sessions: Arc<Mutex<HashMap<u64, Session>>>,
+    // signals waiting for each session's handler, so sessions negotiate independently
+    session_queues: Arc<Mutex<HashMap<u64, SignalSender>>>,
+    session_ended_tx: mpsc::UnboundedSender<u64>,
+    session_event_tx: SessionEventSender,
 }
 
 impl WebRtc {
-    pub async fn new(outgoing_signal_tx: SignalSender) -> Result<Self> {
+    pub async fn new(
+        outgoing_signal_tx: SignalSender,
+        session_event_tx: SessionEventSender,
+        session_options: SessionOptions,
+    ) -> Result<Arc<Self>> {
         let main_loop = glib::MainLoop::new(None, false);
+        let main_loop_clone = main_loop.clone();
         std::thread::spawn(move || {
-            main_loop.run();
+            main_loop_clone.run();
         });
 
         gst::init()?;
 
         let outgoing_signal_tx = Arc::new(outgoing_signal_tx);
 
-        let session = Session::start(0, outgoing_signal_tx.clone())?;
-        let session = Arc::new(Mutex::new(session));
+        let sessions: Arc<Mutex<HashMap<u64, Session>>> = Default::default();
+        let session_queues: Arc<Mutex<HashMap<u64, SignalSender>>> = Default::default();
+
+        // remove sessions as they end, their handlers stop once the queue is drained;
+        // the two maps are never locked at the same time
+        let (session_ended_tx, mut session_ended_rx) = mpsc::unbounded_channel::<u64>();
+        let sessions_clone = sessions.clone();
+        let session_queues_clone = session_queues.clone();
+        tokio::spawn(async move {
+            while let Some(session_id) = session_ended_rx.recv().await {
+                if sessions_clone.lock().await.remove(&session_id).is_some() {
+                    println!("[WebRtc] REMOVED SESSION {session_id}");
+                }
+                session_queues_clone.lock().await.remove(&session_id);
+            }
+        });
+
+        Ok(Arc::new(Self {
+            main_loop,
+            session_options: StdMutex::new(session_options),
+            outgoing_signal_tx,
+            sessions,
+            session_queues,
+            session_ended_tx,
+            session_event_tx,
+        }))
+    }
+
+    /// Say bye to every peer, wind down all sessions and stop the GLib main loop.
+    pub async fn shutdown(&self) {
+        println!("[WebRtc] SHUTDOWN");
+
+        let sessions: Vec<Session> = self.sessions.lock().await.values().cloned().collect();
+        let results = futures_util::future::join_all(
+            sessions
+                .iter()
+                .map(|session| session.shutdown(SHUTDOWN_EOS_TIMEOUT)),
+        )
+        .await;
+
+        for (session, result) in sessions.iter().zip(results) {
+            if let Err(err) = result {
+                let session_id = session.session_id();
+                println!("[WebRtc] session {session_id} failed to shut down: {err:?}");
+            }
+        }
+
+        // drop them now rather than waiting for their ended notifications
+        self.sessions.lock().await.clear();
+        self.session_queues.lock().await.clear();
+
+        self.main_loop.quit();
+    }
 
-        Ok(Self {
-            session,
-            // outgoing_signal_tx,
-        })
+    /// Look up a running session, e.g. on a `SessionEvent`.
+    pub async fn session(&self, session_id: u64) -> Option<Session> {
+        self.sessions.lock().await.get(&session_id).cloned()
     }
 
-    pub async fn on_incoming_signal(&self, signal: Signal) {
+    /// Handle a signal from the peer. Those for a session are queued for its
+    /// own handler, so one session's negotiation doesn't hold up the others.
+    pub async fn on_incoming_signal(self: &Arc<Self>, signal: Signal) {
         println!("[WebRtc] INCOMING SIGNAL {signal:?}");
 
-        let mut session = self.session.lock().await;
+        match queued_session_id(&signal) {
+            Some(session_id) => self.queue_signal(session_id, signal).await,
+            None => self.handle_signal(signal).await,
+        }
+    }
+
+    async fn queue_signal(self: &Arc<Self>, session_id: u64, signal: Signal) {
+        let mut session_queues = self.session_queues.lock().await;
+        let queue = session_queues.entry(session_id).or_insert_with(|| {
+            let (queue_tx, queue_rx) = mpsc::unbounded_channel();
+            tokio::spawn(self.clone().handle_session_signals(session_id, queue_rx));
+            queue_tx
+        });
+        // the handler only goes away with its queue, under this lock
+        let _ = queue.send(signal);
+    }
+
+    // one session's signals, in the order they came
+    async fn handle_session_signals(
+        self: Arc<Self>,
+        session_id: u64,
+        mut queue_rx: SignalReceiver,
+    ) {
+        let mut next = queue_rx.recv().await;
+        while let Some(signal) = next {
+            self.handle_signal(signal).await;
+
+            // only this handler starts the session, so this holds until it
+            // takes another signal; looked up before locking the queues, the
+            // two maps are never locked at the same time
+            let session_gone = self.session(session_id).await.is_none();
 
+            // signals are queued under this lock, so none can slip in while we decide
+            let mut session_queues = self.session_queues.lock().await;
+            next = match queue_rx.try_recv() {
+                Ok(signal) => Some(signal),
+                // e.g. an answer for an unknown session, nothing to wait for
+                Err(_) if session_gone => {
+                    session_queues.remove(&session_id);
+                    None
+                }
+                Err(_) => {
+                    drop(session_queues);
+                    queue_rx.recv().await
+                }
+            };
+        }
+    }
+
+    async fn handle_signal(&self, signal: Signal) {
+        // sessions are cloned out of the map, so it isn't locked while negotiating
         match signal {
+            Signal::SessionRequest { data } => {
+                let SessionRequestData { session_id } = data;
+
+                if !self.session_options().backend_offers {
+                    println!(
+                        "[WebRtc] session request {session_id} but we don't make offers, ignored"
+                    );
+                    return;
+                }
+
+                let session = match self.sessions.lock().await.entry(session_id) {
+                    // renegotiate
+                    Entry::Occupied(entry) => entry.get().clone(),
+                    // the offer is sent once webrtcbin needs negotiation
+                    Entry::Vacant(entry) => {
+                        if let Some(session) = self.start_session(session_id) {
+                            entry.insert(session);
+                        }
+                        return;
+                    }
+                };
+
+                if let Err(err) = session.make_offer().await {
+                    session.fail(err);
+                }
+            }
             Signal::WebRtcOffer { data } => {
-                let WebRtcOfferData { webrtc_data, .. } = data;
-                session.on_remote_offer(webrtc_data).unwrap();
+                let WebRtcOfferData {
+                    session_id,
+                    webrtc_data,
+                } = data;
+
+                // an offer for an unknown session starts a new one
+                let session = match self.sessions.lock().await.entry(session_id) {
+                    Entry::Occupied(entry) => entry.get().clone(),
+                    Entry::Vacant(entry) => match self.start_session(session_id) {
+                        Some(session) => entry.insert(session).clone(),
+                        None => return,
+                    },
+                };
+
+                if let Err(err) = session.on_remote_offer(webrtc_data).await {
+                    session.fail(err);
+                }
+            }
+            Signal::WebRtcAnswer { data } => {
+                let WebRtcAnswerData {
+                    session_id,
+                    webrtc_data,
+                } = data;
+
+                match self.session(session_id).await {
+                    Some(session) => {
+                        if let Err(err) = session.on_remote_answer(webrtc_data).await {
+                            session.fail(err);
+                        }
+                    }
+                    None => println!("[WebRtc] answer for unknown session {session_id}, dropped"),
+                }
+            }
+            Signal::IceCandidate { data } => {
+                let IceCandidateData {
+                    session_id,
+                    webrtc_data,
+                } = data;
+
+                match self.session(session_id).await {
+                    Some(session) => {
+                        if let Err(err) = session.on_remote_ice_candidate(webrtc_data).await {
+                            session.fail(err);
+                        }
+                    }
+                    None => {
+                        println!("[WebRtc] ICE candidate for unknown session {session_id}, dropped")
+                    }
+                }
+            }
+            Signal::Bye { data } => {
+                let ByeData { session_id } = data;
+
+                match self.session(session_id).await {
+                    Some(session) => {
+                        if let Err(err) = session.stop() {
+                            println!("[WebRtc] session {session_id} failed to stop: {err:?}");
+                        }
+                    }
+                    None => println!("[WebRtc] bye for unknown session {session_id}, dropped"),
+                }
+            }
+            Signal::IceRestart { data } => {
+                let IceRestartData { session_id } = data;
+
+                match self.session(session_id).await {
+                    Some(session) => {
+                        if let Err(err) = session.restart_ice().await {
+                            session.fail(err);
+                        }
+                    }
+                    None => {
+                        println!("[WebRtc] ICE restart for unknown session {session_id}, dropped")
+                    }
+                }
+            }
+            Signal::IceServersRequest { data } => {
+                let IceServersRequestData { session_id } = data;
+
+                // the peer creates its peer connection with these, before any offer
+                let signal = Signal::IceServers {
+                    data: IceServersData {
+                        session_id,
+                        ice_servers: self.session_options().ice_servers,
+                    },
+                };
+                if let Err(err) = self.outgoing_signal_tx.send(signal) {
+                    println!("[WebRtc] failed to send ICE servers to {session_id}: {err:?}");
+                }
+            }
+            Signal::StunServer { data } => {
+                let StunServerData { urls } = data;
+
+                // from the signal server, so the browser gets it too with our ICE servers
+                let mut session_options = self
+                    .session_options
+                    .lock()
+                    .expect("failed to lock session options");
+                let ice_servers = &mut session_options.ice_servers;
+                if ice_servers.0.iter().any(|server| server.urls == urls) {
+                    return;
+                }
+                println!("[WebRtc] using signal server's STUN server {urls:?}");
+                ice_servers.0.insert(
+                    0,
+                    IceServer {
+                        urls,
+                        username: None,
+                        credential: None,
+                    },
+                );
+            }
+            Signal::TurnServer { data } => {
+                let mut session_options = self
+                    .session_options
+                    .lock()
+                    .expect("failed to lock session options");
+                let ice_servers = &mut session_options.ice_servers;
+                if ice_servers.0.iter().any(|server| server.urls == data.urls) {
+                    return;
+                }
+                println!("[WebRtc] using signal server's TURN server {:?}", data.urls);
+                ice_servers.0.insert(0, data);
+            }
+            Signal::IceServers { .. } => {
+                println!("[WebRtc] ICE servers come from our config, peer's ignored");
+            }
+            Signal::Error { data } => {
+                let ErrorData {
+                    session_id,
+                    code,
+                    message,
+                    fatal,
+                } = data;
+                println!(
+                    "[WebRtc] session {session_id} peer error ({code}, fatal {fatal}) {message}"
+                );
             }
-            _ => {}
         }
     }
+
+    fn session_options(&self) -> SessionOptions {
+        self.session_options
+            .lock()
+            .expect("failed to lock session options")
+            .clone()
+    }
+
+    fn start_session(&self, session_id: u64) -> Option<Session> {
+        println!("[WebRtc] NEW SESSION {session_id}");
+        match Session::start(
+            session_id,
+            self.session_options(),
+            self.outgoing_signal_tx.clone(),
+            self.session_ended_tx.clone(),
+            self.session_event_tx.clone(),
+        ) {
+            Ok(session) => Some(session),
+            Err(err) => {
+                println!("[WebRtc] failed to start session {session_id}: {err}");
+
+                // there's no session to fail, so tell the peer from here
+                let _ = self.outgoing_signal_tx.send(Signal::Error {
+                    data: ErrorData {
+                        session_id,
+                        code: err.code().to_string(),
+                        message: err.to_string(),
+                        fatal: true,
+                    },
+                });
+                None
+            }
+        }
+    }
+}
+
+// session whose handler a signal is queued for, None for those handled straight away
+fn queued_session_id(signal: &Signal) -> Option<u64> {
+    match signal {
+        Signal::SessionRequest { data } => Some(data.session_id),
+        Signal::WebRtcOffer { data } => Some(data.session_id),
+        Signal::WebRtcAnswer { data } => Some(data.session_id),
+        Signal::IceCandidate { data } => Some(data.session_id),
+        Signal::IceRestart { data } => Some(data.session_id),
+        Signal::Bye { data } => Some(data.session_id),
+        _ => None,
+    }
 }
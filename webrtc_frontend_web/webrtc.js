@@ -2,12 +2,44 @@ var signals_ws;
 var peer = null;
 var connected = false;
 var data_channel = null;
+var session_id = 0;
+var test_track = null;
+
+// perfect negotiation, the backend is the impolite peer
+const polite = true;
+var making_offer = false;
+
+// waiting for the backend's ICE servers
+var on_ice_servers = null;
+const ICE_SERVERS_TIMEOUT_MS = 2000;
 
 function on_load() {
   document.getElementById("connect_button").onclick = async () => {
     await connect_webrtc();
   };
 
+  document.getElementById("receive_button").onclick = async () => {
+    await request_webrtc();
+  };
+
+  document.getElementById("add_track_button").onclick = () => {
+    add_test_track();
+  };
+
+  document.getElementById("remove_track_button").onclick = () => {
+    remove_test_track();
+  };
+
+  document.getElementById("glare_button").onclick = () => {
+    force_glare();
+  };
+
+  document.getElementById("ice_restart_button").onclick = () => {
+    if (!peer) return;
+    // backend answers with a new offer
+    send_signal("ice_restart", { session_id });
+  };
+
   document.getElementById("disconnect_button").onclick = () => {
     disconnect_webrtc();
   };
@@ -32,10 +64,53 @@ function init_webrtc() {
 }
 
 async function connect_webrtc() {
-  console.info("connecting...");
+  await create_peer();
+
+  // so the offer has m-lines for the backend's test media (--video-source/--audio-source test)
+  peer.addTransceiver("video", { direction: "recvonly" });
+  peer.addTransceiver("audio", { direction: "recvonly" });
+
+  // onnegotiationneeded makes the offer, once for all of these
+  open_data_channel();
+}
+
+// backend makes the offer (run it with --backend-offers)
+async function request_webrtc() {
+  await create_peer();
+
+  send_signal("session_request", { session_id });
+}
+
+// same STUN/TURN servers as the backend
+function fetch_ice_servers() {
+  return new Promise((resolve) => {
+    const timeout = setTimeout(() => {
+      console.warn("no ICE servers from backend, using none");
+      on_ice_servers = null;
+      resolve([]);
+    }, ICE_SERVERS_TIMEOUT_MS);
+
+    on_ice_servers = (ice_servers) => {
+      clearTimeout(timeout);
+      on_ice_servers = null;
+      resolve(ice_servers);
+    };
+
+    send_signal("ice_servers_request", { session_id });
+  });
+}
+
+async function create_peer() {
+  // new backend session for each connection
+  session_id = Math.floor(Math.random() * 2 ** 32);
+
+  console.info("connecting, session", session_id);
   connected = false;
 
-  peer = new RTCPeerConnection();
+  const ice_servers = await fetch_ice_servers();
+  console.info("ICE servers", ice_servers);
+
+  peer = new RTCPeerConnection({ iceServers: ice_servers });
 
   ////////////////////////////////////////////////////////////////////////////
 
@@ -50,12 +125,20 @@ async function connect_webrtc() {
     if (candidate && candidate.candidate) {
       // wtf?
       send_signal("ice_candidate", {
-        session_id: 0,
+        session_id,
         webrtc_data: {
           candidate: candidate.candidate,
+          mid: candidate.sdpMid,
           sdpMLineIndex: candidate.sdpMLineIndex,
+          usernameFragment: candidate.usernameFragment,
         },
       });
+    } else if (!candidate) {
+      // gathering finished, end-of-candidates for every m-line
+      send_signal("ice_candidate", {
+        session_id,
+        webrtc_data: { candidate: "" },
+      });
     }
   };
 
@@ -73,6 +156,11 @@ async function connect_webrtc() {
     check_connected();
   };
 
+  peer.ontrack = (event) => {
+    console.info("remote track", event.track.kind, "mid", event.transceiver.mid);
+    show_remote_track(event.track);
+  };
+
   peer.ondatachannel = (event) => {
     const { channel } = event;
     console.info("[Data Channel] data channel", channel);
@@ -89,22 +177,21 @@ async function connect_webrtc() {
       console.info("[Data Channel] MESSAGE", event);
     };
   };
-
-  ////////////////////////////////////////////////////////////////////////////
-
-  await make_offer();
-
-  open_data_channel();
 }
 
 async function make_offer() {
-  const offer = await peer.createOffer();
-  peer.setLocalDescription(offer);
-
-  send_signal("webrtc_offer", {
-    session_id: 0,
-    webrtc_data: { type: "offer", sdp: offer.sdp },
-  });
+  try {
+    making_offer = true;
+    const offer = await peer.createOffer();
+    await peer.setLocalDescription(offer);
+
+    send_signal("webrtc_offer", {
+      session_id,
+      webrtc_data: { type: "offer", sdp: offer.sdp },
+    });
+  } finally {
+    making_offer = false;
+  }
 }
 
 function send_signal(type, data) {
@@ -122,7 +209,16 @@ function on_signal(type, data) {
 
   const { webrtc_data } = data;
 
+  if (data.session_id != session_id) {
+    console.info("[Signals] not our session, ignored");
+    return;
+  }
+
   switch (type) {
+    case "webrtc_offer":
+      on_offer(webrtc_data);
+      break;
+
     case "webrtc_answer":
       on_answer(webrtc_data);
       break;
@@ -130,17 +226,73 @@ function on_signal(type, data) {
     case "ice_candidate":
       on_ice_candidate(webrtc_data);
       break;
+
+    case "bye":
+      console.info("[Signals] BYE from backend");
+      disconnect_webrtc(false);
+      break;
+
+    case "ice_servers":
+      if (on_ice_servers) {
+        on_ice_servers(data.ice_servers);
+      }
+      break;
+
+    case "error":
+      console.error(`[Signals] ERROR from backend (${data.code})`, data.message);
+      if (data.fatal) {
+        disconnect_webrtc(false);
+      }
+      break;
   }
 }
 
+async function on_offer(data) {
+  console.info("[Signals] REMOTE OFFER", data);
+
+  const collision = making_offer || peer.signalingState != "stable";
+  if (collision) {
+    if (!polite) {
+      console.info("[Glare] impolite, ignoring remote offer");
+      return;
+    }
+    // setRemoteDescription rolls back our offer
+    console.info("[Glare] polite, rolling back local offer");
+  }
+
+  await peer.setRemoteDescription(new RTCSessionDescription(data));
+
+  const answer = await peer.createAnswer();
+  await peer.setLocalDescription(answer);
+
+  send_signal("webrtc_answer", {
+    session_id,
+    webrtc_data: { type: "answer", sdp: answer.sdp },
+  });
+}
+
 function on_answer(data) {
   console.info("[Signals] REMOTE ANSWER", data);
   peer.setRemoteDescription(new RTCSessionDescription(data));
 }
 
 function on_ice_candidate(data) {
+  if (!data.candidate) {
+    // empty candidate = remote peer has finished gathering
+    console.info("[Signals] REMOTE END OF CANDIDATES");
+    peer.addIceCandidate({ candidate: "" });
+    return;
+  }
+
   console.info("[Signals] REMOTE ICE CANDIDATE", data);
-  peer.addIceCandidate(new RTCIceCandidate(data));
+  peer.addIceCandidate(
+    new RTCIceCandidate({
+      candidate: data.candidate,
+      sdpMid: data.mid,
+      sdpMLineIndex: data.sdpMLineIndex,
+      usernameFragment: data.usernameFragment,
+    })
+  );
 }
 
 function check_connected() {
@@ -160,13 +312,77 @@ function check_connected() {
   }
 }
 
+// everything the backend sends plays in one stream, whatever its msid
+function show_remote_track(track) {
+  const video = document.getElementById("remote_video");
+  if (!video.srcObject) {
+    video.srcObject = new MediaStream();
+  }
+  video.srcObject.addTrack(track);
+
+  track.onended = () => {
+    if (video.srcObject) {
+      video.srcObject.removeTrack(track);
+    }
+  };
+}
+
 function open_data_channel() {
   console.info("opening data channel...");
   data_channel = peer.createDataChannel("test data channel", { ordered: true });
 }
 
-function disconnect_webrtc() {
+// both sides offer at once (backend run with --backend-offers), check the
+// console here and the backend log for the GLARE decisions
+function force_glare() {
+  if (!peer) return;
+  console.info("forcing offer collision...");
+
+  send_signal("session_request", { session_id });
+  make_offer();
+}
+
+// renegotiation: adding/removing a track triggers onnegotiationneeded
+function add_test_track() {
+  if (!peer || test_track) return;
+  console.info("adding test track...");
+
+  // animated canvas, so no camera is needed
+  const canvas = document.createElement("canvas");
+  canvas.width = 320;
+  canvas.height = 240;
+  const ctx = canvas.getContext("2d");
+  const timer = setInterval(() => {
+    ctx.fillStyle = "black";
+    ctx.fillRect(0, 0, canvas.width, canvas.height);
+    ctx.fillStyle = "white";
+    ctx.fillText(new Date().toISOString(), 10, 20);
+  }, 33);
+
+  const stream = canvas.captureStream(30);
+  const [track] = stream.getVideoTracks();
+  const sender = peer.addTrack(track, stream);
+  test_track = { sender, track, timer };
+}
+
+function remove_test_track() {
+  if (!test_track) return;
+  console.info("removing test track...");
+
+  clearInterval(test_track.timer);
+  test_track.track.stop();
+  if (peer) {
+    peer.removeTrack(test_track.sender);
+  }
+  test_track = null;
+}
+
+function disconnect_webrtc(say_bye = true) {
   console.info("disconnecting...");
+  if (peer && say_bye) {
+    send_signal("bye", { session_id });
+  }
+  remove_test_track();
   if (data_channel) {
     data_channel.close();
     data_channel = null;
@@ -175,5 +391,6 @@ function disconnect_webrtc() {
     peer.close();
     peer = null;
   }
+  document.getElementById("remote_video").srcObject = null;
   connected = false;
 }